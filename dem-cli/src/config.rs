@@ -0,0 +1,52 @@
+use crate::error::CliError;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// What `dem login` remembers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    pub server: String,
+    pub token: String,
+}
+
+/// `$XDG_CONFIG_HOME/dem/config.json`, or `~/.config/dem/config.json`
+fn path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("dem").join("config.json"))
+}
+
+pub fn load() -> Result<Option<Config>, CliError> {
+    let path = match path() {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    match std::fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| CliError::Local(format!("invalid config {}: {e}", path.display()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Save the config, only readable by the current user since it holds the token
+pub fn save(config: &Config) -> Result<PathBuf, CliError> {
+    use std::io::Write;
+    let path = path().ok_or_else(|| {
+        CliError::Local("unable to find the config directory, set $HOME".to_string())
+    })?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&path)?;
+    // Serializing two strings can't fail
+    file.write_all(&serde_json::to_vec_pretty(config).unwrap_or_default())?;
+    Ok(path)
+}
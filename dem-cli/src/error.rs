@@ -0,0 +1,65 @@
+use dem_types::error::Error;
+
+/// Exit code of local failures, like unreadable files or a missing login
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code when the server can't be reached or sends something unexpected
+pub const EXIT_HTTP: i32 = 3;
+/// Errors of the API exit with this plus the code of `dem_types::error::Error`
+pub const EXIT_API: i32 = 10;
+
+#[derive(Debug)]
+pub enum CliError {
+    Local(String),
+    Http(String),
+    Api(Error, String),
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Local(_) => EXIT_FAILURE,
+            CliError::Http(_) => EXIT_HTTP,
+            CliError::Api(code, _) => EXIT_API + *code as i32,
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Local(e) | CliError::Http(e) => f.write_str(e),
+            CliError::Api(code, description) => write!(f, "{code:?}: {description}"),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<std::io::Error> for CliError {
+    fn from(e: std::io::Error) -> Self {
+        CliError::Local(e.to_string())
+    }
+}
+
+impl<T> From<dem_http::apis::Error<T>> for CliError {
+    fn from(e: dem_http::apis::Error<T>) -> Self {
+        match e {
+            dem_http::apis::Error::ResponseError(r) => {
+                match serde_json::from_str::<dem_types::error::ErrResponse>(&r.content) {
+                    Ok(err) => CliError::Api(err.code(), err.description().to_string()),
+                    // Rejected by the authentication guard, before reaching the route
+                    Err(_) if matches!(r.status.as_u16(), 401 | 403) => CliError::Api(
+                        Error::Unauthorized,
+                        "the API token is invalid or lacks the scope needed".to_string(),
+                    ),
+                    Err(_) => {
+                        CliError::Http(format!("server returned {}: {}", r.status, r.content))
+                    }
+                }
+            }
+            dem_http::apis::Error::Reqwest(e) => CliError::Http(e.to_string()),
+            dem_http::apis::Error::Serde(e) => CliError::Http(format!("invalid response: {e}")),
+            dem_http::apis::Error::Io(e) => CliError::Local(e.to_string()),
+        }
+    }
+}
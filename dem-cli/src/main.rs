@@ -0,0 +1,409 @@
+extern crate clap;
+extern crate dem_http;
+extern crate dem_types;
+extern crate serde_json;
+extern crate tokio;
+
+mod config;
+mod error;
+mod manifest;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use dem_http::apis::{configuration::Configuration, default_api};
+use error::CliError;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_SERVER: &str = "http://localhost:8000";
+
+/// Command-line client of the Discord Emoji Manager
+#[derive(Parser)]
+#[clap(name = "dem", version)]
+struct Cli {
+    /// Url of the DEM server, defaults to the one saved by `dem login`
+    #[clap(long, env = "DEM_SERVER", global = true)]
+    server: Option<String>,
+    /// API token, defaults to the one saved by `dem login`
+    #[clap(long, env = "DEM_TOKEN", global = true, hide_env_values = true)]
+    token: Option<String>,
+    /// Print the responses as JSON
+    #[clap(long, global = true)]
+    json: bool,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check an API token and save it with the server url,
+    /// the token is read from stdin when `--token` isn't given
+    Login,
+    /// Show who the token belongs to
+    Whoami,
+    /// Guilds shared by the user and the bot
+    Guilds,
+    /// Emojis of a guild
+    Emojis { guild: u64 },
+    /// Stickers of a guild
+    Stickers { guild: u64 },
+    /// Images uploaded to the store of a guild
+    Uploads { guild: u64 },
+    /// Upload an emoji, or every image of a directory, to the store of a guild
+    Upload {
+        guild: u64,
+        path: PathBuf,
+        /// Name of the emoji, defaults to the file name
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// Publish an image of the store to its guild
+    Publish { guild: u64, uuid: String },
+    /// Delete an emoji of a guild, or an image of its store
+    Delete {
+        #[clap(subcommand)]
+        target: DeleteTarget,
+    },
+    /// Copy emojis and stickers from a guild to another
+    Copy {
+        from: u64,
+        to: u64,
+        #[clap(long = "emoji")]
+        emojis: Vec<u64>,
+        #[clap(long = "sticker")]
+        stickers: Vec<u64>,
+        #[clap(long, value_enum, default_value = "skip")]
+        on_conflict: OnConflict,
+    },
+    /// Compare a guild with a TOML or YAML manifest,
+    /// only shows what would change unless `--apply` is given
+    Sync {
+        guild: u64,
+        manifest: PathBuf,
+        /// Make the guild match the manifest
+        #[clap(long)]
+        apply: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum DeleteTarget {
+    /// An emoji live in the guild
+    Emoji { guild: u64, emoji_id: u64 },
+    /// An image of the store
+    Upload { guild: u64, uuid: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OnConflict {
+    Skip,
+    Rename,
+    Replace,
+}
+
+impl From<OnConflict> for dem_types::api::ConflictStrategy {
+    fn from(c: OnConflict) -> Self {
+        match c {
+            OnConflict::Skip => Self::Skip,
+            OnConflict::Rename => Self::Rename,
+            OnConflict::Replace => Self::Replace,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("error: {e}");
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn configuration(server: &str, token: String) -> Configuration {
+    let mut config = Configuration::new();
+    config.base_path = format!("{}/api", server.trim_end_matches('/'));
+    config.bearer_access_token = Some(token);
+    config
+}
+
+/// The generated models only share their JSON shape with `dem_types`,
+/// a mismatch means the client and the server don't have the same version
+fn convert<T: serde::Serialize, U: serde::de::DeserializeOwned>(value: &T) -> Result<U, CliError> {
+    serde_json::to_value(value)
+        .and_then(serde_json::from_value)
+        .map_err(|e| CliError::Http(format!("unexpected model from the server: {e}")))
+}
+
+/// Print `value` as JSON, or one line per item with the fields at the `columns` pointers
+fn output<T: serde::Serialize>(json: bool, value: &T, columns: &[&str]) {
+    let value = serde_json::to_value(value).unwrap_or_default();
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&value).unwrap_or_default()
+        );
+        return;
+    }
+    let rows = match value {
+        serde_json::Value::Array(rows) => rows,
+        row => vec![row],
+    };
+    for row in rows {
+        let line = columns
+            .iter()
+            .map(|c| match row.pointer(c) {
+                Some(serde_json::Value::String(s)) => s.clone(),
+                None | Some(serde_json::Value::Null) => "-".to_string(),
+                Some(v) => v.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\t");
+        println!("{line}");
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), CliError> {
+    let saved = config::load()?;
+    let server = cli
+        .server
+        .or_else(|| saved.as_ref().map(|c| c.server.clone()))
+        .unwrap_or_else(|| DEFAULT_SERVER.to_string());
+
+    if let Command::Login = cli.command {
+        let token = match cli.token {
+            Some(t) => t,
+            None => {
+                eprintln!("API token:");
+                let mut line = String::new();
+                std::io::stdin().read_line(&mut line)?;
+                line.trim().to_string()
+            }
+        };
+        let user = default_api::api_get_current_user(&configuration(&server, token.clone()))
+            .await?
+            .ok
+            .ok_or_else(|| {
+                CliError::Api(
+                    dem_types::error::Error::Unauthorized,
+                    "invalid API token".to_string(),
+                )
+            })?;
+        let path = config::save(&config::Config { server, token })?;
+        if !cli.json {
+            eprintln!("Login saved in {}", path.display());
+        }
+        output(cli.json, &user, &["/id", "/username"]);
+        return Ok(());
+    }
+
+    let token = cli
+        .token
+        .or_else(|| saved.map(|c| c.token))
+        .ok_or_else(|| {
+            CliError::Local("not logged in, use `dem login` or set DEM_TOKEN".to_string())
+        })?;
+    let config = configuration(&server, token);
+    let json = cli.json;
+    match cli.command {
+        Command::Login => unreachable!("handled above"),
+        Command::Whoami => {
+            let user = default_api::api_get_current_user(&config).await?.ok;
+            output(json, &user, &["/id", "/username"]);
+        }
+        Command::Guilds => {
+            let guilds = default_api::api_get_overlapping_guilds(&config).await?.ok;
+            output(json, &guilds, &["/id", "/name"]);
+        }
+        Command::Emojis { guild } => {
+            let emojis = default_api::api_get_guild_emojis(&config, guild).await?.ok;
+            output(json, &emojis, &["/id", "/name", "/animated"]);
+        }
+        Command::Stickers { guild } => {
+            let stickers = default_api::api_get_guild_stickers(&config, guild)
+                .await?
+                .ok;
+            output(json, &stickers, &["/id", "/name", "/tags"]);
+        }
+        Command::Uploads { guild } => {
+            let images =
+                default_api::image_image_list(&config, guild, None, None, None, None, None, None)
+                    .await?
+                    .ok;
+            output(json, &images, &["/uuid", "/name", "/kind", "/status"]);
+        }
+        Command::Upload { guild, path, name } => {
+            upload(&config, json, guild, &path, name).await?;
+        }
+        Command::Publish { guild, uuid } => {
+            let published = default_api::image_publish_to_guild(&config, guild, &uuid)
+                .await?
+                .ok;
+            output(json, &published, &["/emoji/id", "/sticker/id"]);
+        }
+        Command::Delete {
+            target: DeleteTarget::Emoji { guild, emoji_id },
+        } => {
+            default_api::api_delete_guild_emoji(&config, guild, emoji_id).await?;
+        }
+        Command::Delete {
+            target: DeleteTarget::Upload { guild, uuid },
+        } => {
+            default_api::image_store_delete(&config, guild, &uuid).await?;
+        }
+        Command::Copy {
+            from,
+            to,
+            emojis,
+            stickers,
+            on_conflict,
+        } => {
+            let request = dem_types::api::CopyRequest {
+                target: to,
+                emojis,
+                stickers,
+                on_conflict: on_conflict.into(),
+            };
+            let result = default_api::api_copy_to_guild(&config, from, convert(&request)?)
+                .await?
+                .ok;
+            let result: dem_types::api::CopyResult = convert(&result)?;
+            let items = result
+                .emojis
+                .iter()
+                .chain(result.stickers.iter())
+                .collect::<Vec<_>>();
+            output(json, &items, &["/source_id", "/name", "/status", "/error"]);
+            let failed = items
+                .iter()
+                .filter(|i| i.status == dem_types::api::CopyStatus::Failed)
+                .count();
+            if failed > 0 {
+                return Err(CliError::Api(
+                    dem_types::error::Error::DiscordAPI,
+                    format!("{failed} items couldn't be copied"),
+                ));
+            }
+        }
+        Command::Sync {
+            guild,
+            manifest,
+            apply,
+        } => {
+            let request = manifest::Manifest::read(&manifest)
+                .await?
+                .into_request(&manifest)
+                .await?;
+            let result =
+                default_api::manifest_sync_guild(&config, guild, convert(&request)?, Some(apply))
+                    .await?
+                    .ok;
+            let result: dem_types::api::SyncResult = convert(&result)?;
+            output(
+                json,
+                &result.items,
+                &[
+                    "/kind",
+                    "/action",
+                    "/name",
+                    "/previous_name",
+                    "/id",
+                    "/error",
+                ],
+            );
+            let failed = result.items.iter().filter(|i| i.error.is_some()).count();
+            if failed > 0 {
+                return Err(CliError::Api(
+                    dem_types::error::Error::DiscordAPI,
+                    format!("{failed} items couldn't be synced"),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn content_type(path: &Path) -> Option<&'static str> {
+    match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+        "png" => Some("image/png"),
+        "gif" => Some("image/gif"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// The file name without what the server refuses in emoji names
+fn emoji_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .take(32)
+        .collect()
+}
+
+/// Upload a file, or every image directly in a directory, and report each of them.
+/// Fails with the error of the first failed upload.
+async fn upload(
+    config: &Configuration,
+    json: bool,
+    guild: u64,
+    path: &Path,
+    name: Option<String>,
+) -> Result<(), CliError> {
+    let files = if tokio::fs::metadata(path).await?.is_dir() {
+        if name.is_some() {
+            return Err(CliError::Local(
+                "--name can't be used when uploading a directory".to_string(),
+            ));
+        }
+        let mut files = Vec::new();
+        let mut dir = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            if entry.file_type().await?.is_file() && content_type(&entry.path()).is_some() {
+                files.push(entry.path());
+            }
+        }
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut results = Vec::with_capacity(files.len());
+    let mut first_error = None;
+    for file in files {
+        let name = name.clone().unwrap_or_else(|| emoji_name(&file));
+        let res = async {
+            let content_type = content_type(&file).ok_or_else(|| {
+                CliError::Local("only png, gif, jpeg and webp files can be uploaded".to_string())
+            })?;
+            let data = tokio::fs::read(&file).await?;
+            default_api::image_upload_emoji_to_store(config, guild, &name, content_type, data)
+                .await
+                .map(|r| r.ok)
+                .map_err(CliError::from)
+        }
+        .await;
+        let mut row = serde_json::json!({
+            "file": file.display().to_string(),
+            "name": name,
+        });
+        match res {
+            Ok(uploaded) => {
+                row["result"] = serde_json::to_value(&uploaded).unwrap_or_default();
+            }
+            Err(e) => {
+                row["error"] = e.to_string().into();
+                row["exit_code"] = e.exit_code().into();
+                first_error.get_or_insert(e);
+            }
+        }
+        results.push(row);
+    }
+    output(json, &results, &["/file", "/result/uuid", "/error"]);
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,91 @@
+use crate::error::CliError;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The emojis and stickers a guild should have, written in TOML or YAML.
+/// Files are relative to the manifest.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Delete the emojis and stickers that aren't listed
+    #[serde(default)]
+    pub prune: bool,
+    #[serde(default)]
+    pub emojis: Vec<Emoji>,
+    #[serde(default)]
+    pub stickers: Vec<Sticker>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Emoji {
+    pub name: String,
+    pub file: PathBuf,
+    /// Existing emoji this entry is about, to rename it
+    pub id: Option<u64>,
+    #[serde(default)]
+    pub roles: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sticker {
+    pub name: String,
+    pub file: PathBuf,
+    /// Existing sticker this entry is about, to rename it
+    pub id: Option<u64>,
+    pub description: Option<String>,
+    pub tags: String,
+}
+
+impl Manifest {
+    pub async fn read(path: &Path) -> Result<Self, CliError> {
+        let content = tokio::fs::read_to_string(path).await?;
+        let invalid =
+            |e: String| CliError::Local(format!("invalid manifest {}: {e}", path.display()));
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| invalid(e.to_string())),
+            Some("yaml" | "yml") => {
+                serde_yaml::from_str(&content).map_err(|e| invalid(e.to_string()))
+            }
+            _ => Err(CliError::Local(
+                "the manifest must be a .toml, .yaml or .yml file".to_string(),
+            )),
+        }
+    }
+
+    /// Read the files of the manifest at `path` to build the request sent to the server
+    pub async fn into_request(self, path: &Path) -> Result<dem_types::api::SyncRequest, CliError> {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let read = |file: PathBuf| async move {
+            let file = dir.join(file);
+            tokio::fs::read(&file)
+                .await
+                .map(base64::encode)
+                .map_err(|e| CliError::Local(format!("{}: {e}", file.display())))
+        };
+        let mut request = dem_types::api::SyncRequest {
+            emojis: Vec::with_capacity(self.emojis.len()),
+            stickers: Vec::with_capacity(self.stickers.len()),
+            prune: self.prune,
+        };
+        for emoji in self.emojis {
+            request.emojis.push(dem_types::api::ManifestEmoji {
+                image: read(emoji.file).await?,
+                name: emoji.name,
+                id: emoji.id,
+                roles: emoji.roles,
+            });
+        }
+        for sticker in self.stickers {
+            request.stickers.push(dem_types::api::ManifestSticker {
+                image: read(sticker.file).await?,
+                name: sticker.name,
+                id: sticker.id,
+                description: sticker.description,
+                tags: sticker.tags,
+            });
+        }
+        Ok(request)
+    }
+}
@@ -44,8 +44,12 @@ fn guild_list_item(
         <AppLink to={Routes::Guild {id: *id} }>
         <div class={css!("padding-top: 0px; margin-top: 0px; display: flex; flex-direction: row; flex-wrap: nowrap; justify-content: flex-start; align-items: center; height: 4em;")}
             onclick={onclick.reform(|_| ())}>
-            <img class={css!("width: 3em; height: 3em; border-radius: 50%; padding-right: 1em;")}
-                src={yew::virtual_dom::AttrValue::from(format!("https://cdn.discordapp.com/icons/{id}/{icon}.png?size=1024"))} />
+            if let Some(icon) = icon {
+                <img class={css!("width: 3em; height: 3em; border-radius: 50%; padding-right: 1em;")}
+                    src={yew::virtual_dom::AttrValue::from(format!("https://cdn.discordapp.com/icons/{id}/{icon}.png?size=1024"))} />
+            } else {
+                <div class={css!("width: 3em; height: 3em; padding-right: 1em;")} />
+            }
             <span class={css!("text-decoration: none; color: var(--mdc-theme-on-surface);")}>{name}</span>
         </div>
         </AppLink>
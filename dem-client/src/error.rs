@@ -45,6 +45,9 @@ macro_rules! gen_error_name {
                                 dem_http::models::error::Error::InvalidRequest => {
                                     "Invalid Request"
                                 }
+                                dem_http::models::error::Error::QuotaExceeded => {
+                                    "Quota Exceeded"
+                                }
                             }
                         } else {
                             "Unknown"
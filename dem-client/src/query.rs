@@ -91,10 +91,10 @@ impl Query for UserGuildsQuery {
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub struct UploadEmojiToStoreMutation(String);
+pub struct UploadEmojiToStoreMutation(dem_http::models::UploadResult);
 
 impl Deref for UploadEmojiToStoreMutation {
-    type Target = String;
+    type Target = dem_http::models::UploadResult;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
@@ -152,6 +152,12 @@ impl Query for GetUploadedEmojisQuery {
         dem_http::apis::default_api::image_image_list(
             &*states.get_atom_value::<crate::APIConfig>(),
             *input,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
         )
         .await
         .map(|v| Self(v.ok).into())
@@ -1,5 +1,6 @@
 use crate::{Error, Rsp};
-use std::collections::HashMap;
+use dem_types::api::{ConflictStrategy, CopyItemResult, CopyStatus};
+use std::collections::{HashMap, HashSet};
 
 #[openapi]
 #[get("/overlapping_guilds")]
@@ -7,24 +8,39 @@ pub async fn get_overlapping_guilds(
     user: crate::auth::User,
     logic: &rocket::State<crate::discord::Logic>,
 ) -> Rsp<Vec<dem_types::discord::PartialGuildWithPermission>> {
-    let user_guilds: HashMap<u64, u64, _> = match logic
-        .get_guilds_of_client_with_permission(&user.token)
+    let logged_user = logic
+        .user_cache
+        .write()
         .await
-    {
-        Ok(v) => v,
-        Err(e) => {
-            error!("Error when requesting user's guilds: {e}");
-            return Rsp::err(Error::DiscordAPI, Some(format!("{e}")));
-        }
+        .get(&user.session)
+        .map(|u| (u.user_id, u.guilds.clone()));
+    let user_id = logged_user.as_ref().map(|(id, _)| *id);
+    let user_guilds: HashMap<u64, u64, _> = match &user.token {
+        Some(token) => match logic.get_guilds_of_client_with_permission(token).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Error when requesting user's guilds: {e}");
+                return Rsp::err(Error::DiscordAPI, Some(format!("{e}")));
+            }
+        },
+        // API tokens only know the guilds from the gateway cache
+        None => match logged_user {
+            Some((_, guilds)) => guilds,
+            None => return Rsp::err(Error::Unauthorized, None),
+        },
     };
     let overlapping = {
         let mut guilds = Vec::with_capacity(200);
         for entry in logic.guilds.iter() {
             if user_guilds.contains_key(entry.key()) {
+                // Prefer the permissions computed from the gateway cache over the login snapshot
+                let permissions = user_id
+                    .and_then(|id| entry.value().permissions_of(id))
+                    .unwrap_or(user_guilds[entry.key()]);
                 guilds.push(
                     dem_types::discord::PartialGuildWithPermission::from_partial_guild(
                         entry.value().clone(),
-                        user_guilds[entry.key()],
+                        permissions,
                     ),
                 )
             }
@@ -41,7 +57,9 @@ pub async fn get_current_user(
     logic: &rocket::State<crate::discord::Logic>,
 ) -> Rsp<Option<crate::dem_types::api::UserLogin>> {
     match user {
-        Ok(crate::auth::User { token }) => match logic.get_user(&token).await {
+        Ok(crate::auth::User {
+            token: Some(token), ..
+        }) => match logic.get_user(&token).await {
             Ok(u) => Rsp::ok(Some(dem_types::api::UserLogin {
                 username: u.username,
                 avatar: u.avatar,
@@ -53,9 +71,22 @@ pub async fn get_current_user(
                 Rsp::err(crate::Error::Internal, None)
             }
         },
+        Ok(crate::auth::User { session, .. }) => {
+            let mut user_cache = logic.user_cache.write().await;
+            Rsp::ok(user_cache.get(&session).map(|u| dem_types::api::UserLogin {
+                username: u.username.clone(),
+                avatar: u.user_icon.clone(),
+                id: u.user_id,
+                discriminator: u.discriminator.clone(),
+            }))
+        }
         Err(crate::auth::UserAuthError::InvalidToken | crate::auth::UserAuthError::NoToken) => {
             Rsp::ok(None)
         }
+        Err(crate::auth::UserAuthError::MissingScope) => Rsp::err(
+            crate::Error::Unauthorized,
+            Some("The API token doesn't have the read scope".to_string()),
+        ),
         Err(crate::auth::UserAuthError::InternalError) => Rsp::err(crate::Error::Internal, None),
     }
 }
@@ -67,7 +98,7 @@ pub async fn get_guild_emojis(
     logic: &rocket::State<crate::discord::Logic>,
     id: u64,
 ) -> Rsp<Vec<dem_types::discord::EmojiItem>> {
-    if let Some(u) = logic.user_cache.write().await.get(&user.token) {
+    if let Some(u) = logic.user_cache.write().await.get(&user.session) {
         if u.guilds.contains_key(&id) {
             Rsp::ok(match logic.get_guild(id).map(|kv| (*kv).emojis.clone()) {
                 Some(o) => o,
@@ -83,6 +114,30 @@ pub async fn get_guild_emojis(
     }
 }
 
+/// Emoji and sticker slots used and left in the guild
+#[openapi]
+#[get("/guild/<id>/capacity")]
+pub async fn get_guild_capacity(
+    user: crate::auth::User,
+    logic: &rocket::State<crate::discord::Logic>,
+    id: u64,
+) -> Rsp<dem_types::api::GuildCapacity> {
+    if let Some(u) = logic.user_cache.write().await.get(&user.session) {
+        if u.guilds.contains_key(&id) {
+            Rsp::ok(match logic.get_guild(id).map(|kv| kv.capacity()) {
+                Some(o) => o,
+                None => {
+                    return Rsp::err(Error::Internal, None);
+                }
+            })
+        } else {
+            Rsp::err(Error::Unauthorized, "Not in the guild".to_string().into())
+        }
+    } else {
+        Rsp::err(Error::Unauthorized, None)
+    }
+}
+
 #[openapi]
 #[get("/guild/<id>/stickers")]
 pub async fn get_guild_stickers(
@@ -90,7 +145,7 @@ pub async fn get_guild_stickers(
     logic: &rocket::State<crate::discord::Logic>,
     id: u64,
 ) -> Rsp<Vec<dem_types::discord::StickerItem>> {
-    if let Some(u) = logic.user_cache.write().await.get(&user.token) {
+    if let Some(u) = logic.user_cache.write().await.get(&user.session) {
         if u.guilds.contains_key(&id) {
             Rsp::ok(match logic.get_guild(id).map(|kv| (*kv).stickers.clone()) {
                 Some(o) => o,
@@ -105,3 +160,398 @@ pub async fn get_guild_stickers(
         Rsp::err(Error::Unauthorized, None)
     }
 }
+
+#[openapi]
+#[patch("/guild/<id>/emojis/<emoji_id>", data = "<patch>")]
+pub async fn patch_guild_emoji(
+    user: crate::auth::User,
+    logic: &rocket::State<crate::discord::Logic>,
+    id: u64,
+    emoji_id: u64,
+    patch: rocket::serde::json::Json<dem_types::api::EmojiPatch>,
+) -> Rsp<dem_types::discord::EmojiItem> {
+    if !logic
+        .user_has_permission(
+            &user.session,
+            id,
+            crate::discord::MANAGE_EMOJIS_AND_STICKERS,
+        )
+        .await
+    {
+        return Rsp::err(
+            Error::Unauthorized,
+            "You are not in the guild or don't have permission to do so"
+                .to_string()
+                .into(),
+        );
+    }
+    if let Some(name) = &patch.name {
+        if !crate::image::is_valid_emoji_name(name) {
+            return Rsp::err(Error::InvalidRequest, "Invalid name".to_string().into());
+        }
+    }
+    let emoji = match logic
+        .modify_guild_emoji(id, emoji_id, patch.name.as_deref(), patch.roles.as_deref())
+        .await
+    {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Error when modifying guild emoji: {e}");
+            return Rsp::err(Error::DiscordAPI, Some(format!("{e}")));
+        }
+    };
+    if let Some(mut guild) = logic.guilds.get_mut(&id) {
+        if let Some(cached) = guild.emojis.iter_mut().find(|e| e.id == emoji.id) {
+            *cached = emoji.clone();
+        }
+    }
+    Rsp::ok(emoji)
+}
+
+#[openapi]
+#[delete("/guild/<id>/emojis/<emoji_id>")]
+pub async fn delete_guild_emoji(
+    user: crate::auth::User,
+    logic: &rocket::State<crate::discord::Logic>,
+    id: u64,
+    emoji_id: u64,
+) -> Rsp<()> {
+    if !logic
+        .user_has_permission(
+            &user.session,
+            id,
+            crate::discord::MANAGE_EMOJIS_AND_STICKERS,
+        )
+        .await
+    {
+        return Rsp::err(
+            Error::Unauthorized,
+            "You are not in the guild or don't have permission to do so"
+                .to_string()
+                .into(),
+        );
+    }
+    if let Err(e) = logic.delete_guild_emoji(id, emoji_id).await {
+        error!("Error when deleting guild emoji: {e}");
+        return Rsp::err(Error::DiscordAPI, Some(format!("{e}")));
+    }
+    if let Some(mut guild) = logic.guilds.get_mut(&id) {
+        guild.emojis.retain(|e| e.id != emoji_id);
+    }
+    Rsp::ok(())
+}
+
+#[openapi]
+#[post("/guild/<id>/copy", data = "<request>")]
+pub async fn copy_to_guild(
+    user: crate::auth::User,
+    logic: &rocket::State<crate::discord::Logic>,
+    id: u64,
+    request: rocket::serde::json::Json<dem_types::api::CopyRequest>,
+) -> Rsp<dem_types::api::CopyResult> {
+    let in_source = logic
+        .user_cache
+        .write()
+        .await
+        .get(&user.session)
+        .map(|u| u.guilds.contains_key(&id))
+        .unwrap_or(false);
+    if !in_source {
+        return Rsp::err(Error::Unauthorized, "Not in the guild".to_string().into());
+    }
+    if !logic
+        .user_has_permission(
+            &user.session,
+            request.target,
+            crate::discord::MANAGE_EMOJIS_AND_STICKERS,
+        )
+        .await
+    {
+        return Rsp::err(
+            Error::Unauthorized,
+            "You are not in the target guild or don't have permission to do so"
+                .to_string()
+                .into(),
+        );
+    }
+    if logic.get_guild(request.target).is_none() {
+        return Rsp::err(Error::InvalidRequest, "Unknown target guild".to_string().into());
+    }
+    let (source_emojis, source_stickers) = match logic
+        .get_guild(id)
+        .map(|g| (g.emojis.clone(), g.stickers.clone()))
+    {
+        Some(v) => v,
+        None => {
+            return Rsp::err(Error::InvalidRequest, "Unknown source guild".to_string().into())
+        }
+    };
+
+    if let Err(reason) = refuse_lottie(&source_stickers, &request.stickers) {
+        return Rsp::err(Error::InvalidRequest, Some(reason));
+    }
+
+    let mut result = dem_types::api::CopyResult {
+        emojis: Vec::with_capacity(request.emojis.len()),
+        stickers: Vec::with_capacity(request.stickers.len()),
+    };
+    for emoji_id in &request.emojis {
+        result.emojis.push(
+            match source_emojis.iter().find(|e| e.id == *emoji_id) {
+                Some(emoji) => copy_emoji(logic, request.target, emoji, request.on_conflict).await,
+                None => CopyItemResult::failed(
+                    *emoji_id,
+                    String::new(),
+                    "Not found in the source guild".to_string(),
+                ),
+            },
+        );
+    }
+    for sticker_id in &request.stickers {
+        result.stickers.push(
+            match source_stickers.iter().find(|s| s.id == *sticker_id) {
+                Some(sticker) => {
+                    copy_sticker(logic, request.target, sticker, request.on_conflict).await
+                }
+                None => CopyItemResult::failed(
+                    *sticker_id,
+                    String::new(),
+                    "Not found in the source guild".to_string(),
+                ),
+            },
+        );
+    }
+    Rsp::ok(result)
+}
+
+/// Discord only takes Lottie stickers (format type 3) from its own partners,
+/// a copy asking for one is refused before anything is created
+fn refuse_lottie(
+    stickers: &[dem_types::discord::StickerItem],
+    requested: &[u64],
+) -> Result<(), String> {
+    let lottie = stickers
+        .iter()
+        .filter(|s| s.format_type == Some(3) && requested.contains(&s.id))
+        .map(|s| s.name.as_str())
+        .collect::<Vec<_>>();
+    if lottie.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Lottie stickers can't be uploaded to a guild, only Discord can add them: {}",
+            lottie.join(", ")
+        ))
+    }
+}
+
+/// Find a name that isn't taken by appending a number, keeping it under `max_len`
+fn free_name(name: &str, max_len: usize, taken: &HashSet<String>) -> String {
+    (2..)
+        .map(|n| {
+            let suffix = n.to_string();
+            let base: String = name.chars().take(max_len - suffix.len()).collect();
+            format!("{base}{suffix}")
+        })
+        .find(|candidate| !taken.contains(candidate))
+        .unwrap()
+}
+
+async fn copy_emoji(
+    logic: &crate::discord::Logic,
+    target: u64,
+    emoji: &dem_types::discord::EmojiItem,
+    on_conflict: ConflictStrategy,
+) -> CopyItemResult {
+    let existing = logic
+        .get_guild(target)
+        .map(|g| g.emojis.clone())
+        .unwrap_or_default();
+    let mut name = emoji.name.clone();
+    let mut status = CopyStatus::Copied;
+    // Deleted once its copy is created, so a failed copy doesn't lose it
+    let mut replaced = None;
+    if let Some(conflict) = existing.iter().find(|e| e.name == emoji.name) {
+        match on_conflict {
+            ConflictStrategy::Skip => {
+                return CopyItemResult {
+                    source_id: emoji.id,
+                    name,
+                    status: CopyStatus::Skipped,
+                    new_id: None,
+                    error: None,
+                }
+            }
+            ConflictStrategy::Rename => {
+                let taken = existing.iter().map(|e| e.name.clone()).collect();
+                name = free_name(&emoji.name, 32, &taken);
+                status = CopyStatus::Renamed;
+            }
+            ConflictStrategy::Replace => {
+                replaced = Some(conflict.id);
+                status = CopyStatus::Replaced;
+            }
+        }
+    }
+
+    let bytes = match logic.fetch_asset(&emoji.cdn_url()).await {
+        Ok(b) => b,
+        Err(e) => return CopyItemResult::failed(emoji.id, name, format!("{e}")),
+    };
+    let content_type = if emoji.animated {
+        rocket::http::ContentType::GIF
+    } else {
+        rocket::http::ContentType::PNG
+    };
+    match logic
+        .create_guild_emoji(target, &name, &content_type, &bytes)
+        .await
+    {
+        Ok(new) => {
+            let new_id = new.id;
+            if let Some(mut guild) = logic.guilds.get_mut(&target) {
+                guild.emojis.push(new);
+            }
+            let mut error = None;
+            if let Some(old_id) = replaced {
+                match logic.delete_guild_emoji(target, old_id).await {
+                    Ok(_) => {
+                        if let Some(mut guild) = logic.guilds.get_mut(&target) {
+                            guild.emojis.retain(|e| e.id != old_id);
+                        }
+                    }
+                    Err(e) => {
+                        status = CopyStatus::Copied;
+                        error = Some(format!(
+                            "Copied, but the existing emoji wasn't deleted: {e}"
+                        ));
+                    }
+                }
+            }
+            CopyItemResult {
+                source_id: emoji.id,
+                name,
+                status,
+                new_id: Some(new_id),
+                error,
+            }
+        }
+        Err(e) => CopyItemResult::failed(emoji.id, name, format!("{e}")),
+    }
+}
+
+async fn copy_sticker(
+    logic: &crate::discord::Logic,
+    target: u64,
+    sticker: &dem_types::discord::StickerItem,
+    on_conflict: ConflictStrategy,
+) -> CopyItemResult {
+    let existing = logic
+        .get_guild(target)
+        .map(|g| g.stickers.clone())
+        .unwrap_or_default();
+    let mut name = sticker.name.clone();
+    let mut status = CopyStatus::Copied;
+    // Deleted once its copy is created, so a failed copy doesn't lose it
+    let mut replaced = None;
+    if let Some(conflict) = existing.iter().find(|s| s.name == sticker.name) {
+        match on_conflict {
+            ConflictStrategy::Skip => {
+                return CopyItemResult {
+                    source_id: sticker.id,
+                    name,
+                    status: CopyStatus::Skipped,
+                    new_id: None,
+                    error: None,
+                }
+            }
+            ConflictStrategy::Rename => {
+                let taken = existing.iter().map(|s| s.name.clone()).collect();
+                name = free_name(&sticker.name, 30, &taken);
+                status = CopyStatus::Renamed;
+            }
+            ConflictStrategy::Replace => {
+                replaced = Some(conflict.id);
+                status = CopyStatus::Replaced;
+            }
+        }
+    }
+
+    let bytes = match logic.fetch_asset(&sticker.cdn_url()).await {
+        Ok(b) => b,
+        Err(e) => return CopyItemResult::failed(sticker.id, name, format!("{e}")),
+    };
+    let content_type = match sticker.extension() {
+        "json" => rocket::http::ContentType::JSON,
+        "gif" => rocket::http::ContentType::GIF,
+        _ => rocket::http::ContentType::PNG,
+    };
+    match logic
+        .create_guild_sticker(
+            target,
+            &name,
+            sticker.description.as_deref().unwrap_or_default(),
+            sticker.tags.as_deref().unwrap_or(&sticker.name),
+            &format!("{name}.{}", sticker.extension()),
+            &content_type,
+            bytes,
+        )
+        .await
+    {
+        Ok(new) => {
+            let new_id = new.id;
+            if let Some(mut guild) = logic.guilds.get_mut(&target) {
+                guild.stickers.push(new);
+            }
+            let mut error = None;
+            if let Some(old_id) = replaced {
+                match logic.delete_guild_sticker(target, old_id).await {
+                    Ok(_) => {
+                        if let Some(mut guild) = logic.guilds.get_mut(&target) {
+                            guild.stickers.retain(|s| s.id != old_id);
+                        }
+                    }
+                    Err(e) => {
+                        status = CopyStatus::Copied;
+                        error = Some(format!(
+                            "Copied, but the existing sticker wasn't deleted: {e}"
+                        ));
+                    }
+                }
+            }
+            CopyItemResult {
+                source_id: sticker.id,
+                name,
+                status,
+                new_id: Some(new_id),
+                error,
+            }
+        }
+        Err(e) => CopyItemResult::failed(sticker.id, name, format!("{e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sticker(id: u64, name: &str, format_type: u8) -> dem_types::discord::StickerItem {
+        dem_types::discord::StickerItem {
+            id,
+            name: name.to_string(),
+            description: None,
+            tags: None,
+            format_type: Some(format_type),
+        }
+    }
+
+    #[test]
+    fn lottie_stickers_are_refused() {
+        let stickers = [sticker(1, "png", 1), sticker(2, "wumpus", 3)];
+        assert_eq!(refuse_lottie(&stickers, &[1]), Ok(()));
+        // Only the requested ones matter
+        assert_eq!(refuse_lottie(&stickers, &[]), Ok(()));
+        let reason = refuse_lottie(&stickers, &[1, 2]).unwrap_err();
+        assert!(reason.contains("wumpus"), "{reason}");
+    }
+}
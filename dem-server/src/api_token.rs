@@ -0,0 +1,329 @@
+use crate::{Error, Rsp};
+use dem_types::api::{ApiScope, ApiTokenCreate, ApiTokenCreated, ApiTokenInfo};
+use rocket::serde::json::Json;
+use rocket_db_pools::deadpool_redis::redis::{Cmd, RedisError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Every API token starts with this, makes them easy to spot in logs and configs
+const TOKEN_PREFIX: &str = "dem_";
+/// Users of API tokens are rebuilt from the gateway cache on every request,
+/// this only bounds how long they stay in `Logic::user_cache` afterwards
+pub const CACHE_SECS: u64 = 5 * 60;
+
+/// What is kept in Redis for an API token, only the hash of the token is stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub user_id: u64,
+    pub username: String,
+    pub discriminator: String,
+    pub user_icon: Option<String>,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub guild: Option<u64>,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+}
+
+impl ApiToken {
+    fn key(hash: &str) -> String {
+        format!("dem:api_token:{hash}")
+    }
+
+    /// Hash of the token ids of a user to the hashes of the tokens
+    fn user_key(user_id: u64) -> String {
+        format!("dem:user_api_tokens:{user_id}")
+    }
+
+    fn hash(token: &str) -> String {
+        hex::encode(Sha256::digest(token.as_bytes()))
+    }
+
+    /// Key of the token's user in `Logic::user_cache`
+    pub fn cache_key(id: &str) -> String {
+        format!("api:{id}")
+    }
+
+    /// Find the token sent by a client
+    pub async fn load(
+        con: &mut rocket_db_pools::deadpool_redis::Connection,
+        token: &str,
+    ) -> Result<Option<Self>, RedisError> {
+        if !token.starts_with(TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        let data = Cmd::get(Self::key(&Self::hash(token)))
+            .query_async::<_, Option<String>>(con)
+            .await?;
+        Ok(data.and_then(|d| {
+            serde_json::from_str(&d)
+                .map_err(|e| error!("Invalid API token record: {e}"))
+                .ok()
+        }))
+    }
+
+    pub fn allows(&self, scope: ApiScope) -> bool {
+        self.scopes
+            .iter()
+            .any(|&s| s == scope || s == ApiScope::Admin)
+    }
+
+    fn info(&self) -> ApiTokenInfo {
+        ApiTokenInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            scopes: self.scopes.clone(),
+            guild: self.guild,
+            created_at: self.created_at,
+        }
+    }
+
+    /// The user acting with the token, in the guilds where the member cache has them now.
+    /// A token restricted to a guild only sees that one, its member is fetched
+    /// from Discord when the gateway didn't send it.
+    pub async fn logged_user(&self, logic: &crate::discord::Logic) -> crate::discord::LoggedUser {
+        let mut guilds = std::collections::HashMap::with_hasher(fxhash::FxBuildHasher::default());
+        match self.guild {
+            Some(guild_id) => {
+                if let Some(permissions) = logic.member_permissions(guild_id, self.user_id).await {
+                    guilds.insert(guild_id, permissions);
+                }
+            }
+            None => {
+                for guild in logic.guilds.iter() {
+                    if let Some(permissions) = guild.permissions_of(self.user_id) {
+                        guilds.insert(guild.id, permissions);
+                    }
+                }
+            }
+        }
+        crate::discord::LoggedUser {
+            expires_at: std::time::SystemTime::now() + std::time::Duration::from_secs(CACHE_SECS),
+            user_id: self.user_id,
+            user_icon: self.user_icon.clone(),
+            username: self.username.clone(),
+            discriminator: self.discriminator.clone(),
+            guilds,
+        }
+    }
+}
+
+/// Scope an API token needs for the route handling `req`
+pub fn required_scope(req: &rocket::Request<'_>) -> ApiScope {
+    match req.route().and_then(|r| r.name.as_deref()) {
+        Some(
+            "upload_emoji_to_store" | "upload_sticker_to_store" | "store_patch" | "store_delete",
+        ) => ApiScope::Upload,
+        Some("publish_to_guild" | "copy_to_guild" | "sync_guild") => ApiScope::Publish,
+        _ if req.method() == rocket::http::Method::Get => ApiScope::Read,
+        _ => ApiScope::Admin,
+    }
+}
+
+fn new_token() -> String {
+    use rand::RngCore;
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{TOKEN_PREFIX}{}", hex::encode(bytes))
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The logged user behind a browser session, API tokens can't manage API tokens
+async fn session_user(
+    logic: &crate::discord::Logic,
+    user: &crate::auth::User,
+) -> Option<crate::discord::LoggedUser> {
+    if user.api_token.is_some() {
+        return None;
+    }
+    logic.user_cache.write().await.get(&user.session).cloned()
+}
+
+/// Create an API token, the secret is only returned by this call
+#[openapi]
+#[post("/tokens", data = "<request>")]
+pub async fn create_token(
+    user: crate::auth::User,
+    logic: &rocket::State<crate::discord::Logic>,
+    mut con: crate::Connection<crate::DemDb>,
+    request: Json<ApiTokenCreate>,
+) -> Rsp<ApiTokenCreated> {
+    let logged_user = match session_user(logic, &user).await {
+        Some(u) => u,
+        None => {
+            return Rsp::err(
+                Error::Unauthorized,
+                Some("API tokens can only be managed from a browser session".to_string()),
+            )
+        }
+    };
+    let ApiTokenCreate {
+        name,
+        scopes,
+        guild,
+    } = request.into_inner();
+    let name = name.trim().to_string();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Rsp::err(
+            Error::InvalidRequest,
+            Some("The name must be between 1 and 100 characters".to_string()),
+        );
+    }
+    let scopes = scopes.into_iter().fold(Vec::new(), |mut acc, s| {
+        if !acc.contains(&s) {
+            acc.push(s);
+        }
+        acc
+    });
+    if scopes.is_empty() {
+        return Rsp::err(
+            Error::InvalidRequest,
+            Some("A token needs at least one scope".to_string()),
+        );
+    }
+    if let Some(guild) = guild {
+        if !logged_user.guilds.contains_key(&guild) {
+            return Rsp::err(Error::Unauthorized, "Not in the guild".to_string().into());
+        }
+    }
+
+    let token = new_token();
+    let hash = ApiToken::hash(&token);
+    let record = ApiToken {
+        id: uuid::Uuid::new_v4().to_string(),
+        user_id: logged_user.user_id,
+        username: logged_user.username,
+        discriminator: logged_user.discriminator,
+        user_icon: logged_user.user_icon,
+        name,
+        scopes,
+        guild,
+        created_at: unix_now(),
+    };
+    // Serializing plain strings and integers can't fail
+    let data = serde_json::to_string(&record).unwrap_or_default();
+    let res = async {
+        Cmd::set(ApiToken::key(&hash), data)
+            .query_async::<_, ()>(&mut *con)
+            .await?;
+        Cmd::hset(ApiToken::user_key(record.user_id), &record.id, &hash)
+            .query_async::<_, ()>(&mut *con)
+            .await
+    }
+    .await;
+    if let Err(e) = res {
+        error!("Error when comunicating with redis db: {e}");
+        return Rsp::err(Error::Internal, None);
+    }
+    Rsp::ok(ApiTokenCreated {
+        token,
+        info: record.info(),
+    })
+}
+
+/// The API tokens of the current user
+#[openapi]
+#[get("/tokens")]
+pub async fn list_tokens(
+    user: crate::auth::User,
+    logic: &rocket::State<crate::discord::Logic>,
+    mut con: crate::Connection<crate::DemDb>,
+) -> Rsp<Vec<ApiTokenInfo>> {
+    let user_id = match session_user(logic, &user).await {
+        Some(u) => u.user_id,
+        None => {
+            return Rsp::err(
+                Error::Unauthorized,
+                Some("API tokens can only be managed from a browser session".to_string()),
+            )
+        }
+    };
+    let hashes = match Cmd::hvals(ApiToken::user_key(user_id))
+        .query_async::<_, Vec<String>>(&mut *con)
+        .await
+    {
+        Ok(h) => h,
+        Err(e) => {
+            error!("Error when comunicating with redis db: {e}");
+            return Rsp::err(Error::Internal, None);
+        }
+    };
+    let mut tokens = Vec::with_capacity(hashes.len());
+    for hash in hashes {
+        let data = match Cmd::get(ApiToken::key(&hash))
+            .query_async::<_, Option<String>>(&mut *con)
+            .await
+        {
+            Ok(d) => d,
+            Err(e) => {
+                error!("Error when comunicating with redis db: {e}");
+                return Rsp::err(Error::Internal, None);
+            }
+        };
+        match data.map(|d| serde_json::from_str::<ApiToken>(&d)) {
+            Some(Ok(token)) => tokens.push(token.info()),
+            Some(Err(e)) => error!("Invalid API token record: {e}"),
+            None => {}
+        }
+    }
+    tokens.sort_by_key(|t| t.created_at);
+    Rsp::ok(tokens)
+}
+
+/// Revoke an API token of the current user
+#[openapi]
+#[delete("/tokens/<id>")]
+pub async fn revoke_token(
+    user: crate::auth::User,
+    logic: &rocket::State<crate::discord::Logic>,
+    mut con: crate::Connection<crate::DemDb>,
+    id: String,
+) -> Rsp<()> {
+    let user_id = match session_user(logic, &user).await {
+        Some(u) => u.user_id,
+        None => {
+            return Rsp::err(
+                Error::Unauthorized,
+                Some("API tokens can only be managed from a browser session".to_string()),
+            )
+        }
+    };
+    let user_key = ApiToken::user_key(user_id);
+    let res = async {
+        let hash = Cmd::hget(&user_key, &id)
+            .query_async::<_, Option<String>>(&mut *con)
+            .await?;
+        if let Some(hash) = &hash {
+            Cmd::del(ApiToken::key(hash))
+                .query_async::<_, ()>(&mut *con)
+                .await?;
+            Cmd::hdel(&user_key, &id)
+                .query_async::<_, ()>(&mut *con)
+                .await?;
+        }
+        Ok::<_, RedisError>(hash.is_some())
+    }
+    .await;
+    match res {
+        Ok(true) => {
+            logic
+                .user_cache
+                .write()
+                .await
+                .pop(&ApiToken::cache_key(&id));
+            Rsp::ok(())
+        }
+        Ok(false) => Rsp::err(Error::InvalidRequest, Some("Unknown token".to_string())),
+        Err(e) => {
+            error!("Error when comunicating with redis db: {e}");
+            Rsp::err(Error::Internal, None)
+        }
+    }
+}
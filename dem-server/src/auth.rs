@@ -8,26 +8,186 @@ use rocket::{
     Request, State,
 };
 use rocket_db_pools::deadpool_redis::redis::Cmd;
+use serde::{Deserialize, Serialize};
 
 pub struct Discord;
 
+/// Name of the private cookie holding the session id
+const SESSION_COOKIE: &str = "session";
+/// Sessions not used for this long are forgotten
+const SESSION_TTL: u64 = 30 * 24 * 3600;
+/// Access tokens expiring in less than this are refreshed before being used
+const REFRESH_MARGIN: u64 = 5 * 60;
+/// Seconds a request may spend refreshing a session before another one can try
+const REFRESH_LOCK_SECS: u64 = 30;
+/// How long a request waits for another one to refresh the same session
+const REFRESH_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Why `User::refresh` didn't return a session
+enum RefreshError {
+    /// The session can't be used anymore
+    Invalid,
+    /// Another request is still refreshing it
+    Busy,
+}
+
+/// What is kept in Redis for a session, the Discord tokens never leave the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Session {
+    user_id: u64,
+    access_token: String,
+    refresh_token: Option<String>,
+    /// Seconds since the unix epoch
+    expires_at: u64,
+}
+
+impl Session {
+    fn new(user_id: u64, token: &rocket_oauth2::TokenResponse<Discord>) -> Self {
+        Session {
+            user_id,
+            access_token: token.access_token().to_string(),
+            refresh_token: token.refresh_token().map(ToString::to_string),
+            expires_at: unix_now() + token.expires_in().map_or(3600, |s| s.max(0) as u64),
+        }
+    }
+
+    fn key(id: &str) -> String {
+        format!("dem:session:{id}")
+    }
+
+    /// Set of the session ids of a user, used to log them out everywhere
+    fn user_sessions_key(user_id: u64) -> String {
+        format!("dem:user_sessions:{user_id}")
+    }
+
+    async fn load(
+        con: &mut rocket_db_pools::deadpool_redis::Connection,
+        id: &str,
+    ) -> Result<Option<Self>, rocket_db_pools::deadpool_redis::redis::RedisError> {
+        let data = Cmd::get(Self::key(id))
+            .query_async::<_, Option<String>>(con)
+            .await?;
+        Ok(data.and_then(|d| {
+            serde_json::from_str(&d)
+                .map_err(|e| error!("Invalid session record: {e}"))
+                .ok()
+        }))
+    }
+
+    /// Store the session and add it to the sessions of its user,
+    /// both are kept for `SESSION_TTL` from now
+    async fn save(
+        &self,
+        con: &mut rocket_db_pools::deadpool_redis::Connection,
+        id: &str,
+    ) -> Result<(), rocket_db_pools::deadpool_redis::redis::RedisError> {
+        // Serializing plain strings and integers can't fail
+        let data = serde_json::to_string(self).unwrap_or_default();
+        Cmd::set_ex(Self::key(id), data, SESSION_TTL as usize)
+            .query_async::<_, ()>(&mut *con)
+            .await?;
+        let user_sessions = Self::user_sessions_key(self.user_id);
+        Cmd::sadd(&user_sessions, id)
+            .query_async::<_, ()>(&mut *con)
+            .await?;
+        Cmd::expire(&user_sessions, SESSION_TTL as usize)
+            .query_async::<_, ()>(&mut *con)
+            .await
+    }
+
+    /// Revoke the tokens of the session at Discord
+    async fn revoke(&self, logic: &crate::discord::Logic) {
+        if let Err(e) = logic.revoke_token(&self.access_token, "access_token").await {
+            warn!("Unable to revoke a Discord access token: {e}");
+        }
+        if let Some(refresh_token) = &self.refresh_token {
+            if let Err(e) = logic.revoke_token(refresh_token, "refresh_token").await {
+                warn!("Unable to revoke a Discord refresh token: {e}");
+            }
+        }
+    }
+
+    /// Revoke the tokens at Discord and forget everything about the session `id`
+    async fn end(
+        con: &mut rocket_db_pools::deadpool_redis::Connection,
+        logic: &crate::discord::Logic,
+        id: &str,
+    ) -> Result<(), rocket_db_pools::deadpool_redis::redis::RedisError> {
+        if let Some(session) = Self::load(con, id).await? {
+            session.revoke(logic).await;
+            Cmd::srem(Self::user_sessions_key(session.user_id), id)
+                .query_async::<_, ()>(&mut *con)
+                .await?;
+        }
+        Cmd::del(Self::key(id))
+            .query_async::<_, ()>(&mut *con)
+            .await?;
+
+        if let Some((_, user)) = logic.user_cache.write().await.pop_entry(id) {
+            let mut user_id_to_session = logic.user_id_to_session.write().await;
+            if user_id_to_session.peek(&user.user_id).map(String::as_str) == Some(id) {
+                user_id_to_session.pop(&user.user_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Seconds since the unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn new_session_id() -> String {
+    use rand::RngCore;
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Fetch who the token belongs to and the guilds they are in
+async fn logged_user(
+    logic: &crate::discord::Logic,
+    session: &Session,
+) -> Result<crate::discord::LoggedUser, Box<dyn std::error::Error + Send + Sync>> {
+    let user = logic.get_user(&session.access_token).await?;
+    let guilds = logic
+        .get_guilds_of_client_with_permission(&session.access_token)
+        .await?;
+    Ok(crate::discord::LoggedUser {
+        expires_at: SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(session.expires_at),
+        user_id: user.id,
+        user_icon: user.avatar.map(|s| {
+            if s.starts_with("_a") {
+                s.strip_prefix("_a").unwrap().to_string()
+            } else {
+                s
+            }
+        }),
+        username: user.username,
+        discriminator: user.discriminator,
+        guilds,
+    })
+}
+
 #[get("/")]
 pub async fn login(
     oauth2: rocket_oauth2::OAuth2<Discord>,
     cookies: &CookieJar<'_>,
-    _logic: &State<crate::discord::Logic>,
     mut con: crate::Connection<crate::DemDb>,
 ) -> Result<Redirect, rocket::http::Status> {
-    if let Some(token) = cookies.get_private("token") {
-        // if logic.user_cache.get(token.value())
-        let res = Cmd::get(token.value())
-            .query_async::<_, Option<u64>>(&mut *con)
+    if let Some(session) = cookies.get_private(SESSION_COOKIE) {
+        let res = Cmd::exists(Session::key(session.value()))
+            .query_async::<_, bool>(&mut *con)
             .await
             .map_err(|e| {
                 error!("Error when comunicating with redis db: {e}");
                 rocket::http::Status::InternalServerError
             })?;
-        if res.is_some() {
+        if res {
             return Ok(Redirect::to("/"));
         }
     }
@@ -47,99 +207,246 @@ pub async fn callback(
         error!("Error when communicating with the Discord API: {e}");
         rocket::http::Status::InternalServerError
     })?;
-    Cmd::set_ex::<_, u64>(
-        token.access_token(),
-        user.id,
-        token.expires_in().unwrap_or(i64::MAX).max(0) as usize,
-    )
-    .query_async::<_, ()>(&mut *con)
-    .await
-    .map_err(|e| {
+    let session = Session::new(user.id, &token);
+    // Saved last, a login that fails leaves neither a session nor usable tokens behind
+    let logged_user = match logged_user(logic, &session).await {
+        Ok(u) => u,
+        Err(e) => {
+            error!("Error when communicating with Discord API: {e}");
+            session.revoke(logic).await;
+            return Err(rocket::http::Status::InternalServerError);
+        }
+    };
+    let session_id = new_session_id();
+    if let Err(e) = session.save(&mut con, &session_id).await {
         error!("Error when comunicating with redis db: {e}");
-        rocket::http::Status::InternalServerError
-    })?;
-
-    let guilds = logic
-        .get_guilds_of_client_with_permission(token.access_token())
+        let _ = Cmd::del(Session::key(&session_id))
+            .query_async::<_, ()>(&mut *con)
+            .await;
+        session.revoke(logic).await;
+        return Err(rocket::http::Status::InternalServerError);
+    }
+    logic
+        .user_id_to_session
+        .write()
         .await
-        .map_err(|e| {
-            error!("Error when communicating with Discord API: {e}");
-            rocket::http::Status::InternalServerError
-        })?;
-
+        .push(user.id, session_id.clone());
     logic
-        .user_id_to_token
+        .user_cache
         .write()
         .await
-        .push(user.id, token.access_token().to_string());
-    logic.user_cache.write().await.push(
-        token.access_token().to_string(),
-        crate::discord::LoggedUser {
-            expires_at: SystemTime::now()
-                + std::time::Duration::from_secs(
-                    token.expires_in().map(|s| s.max(0) as u64).unwrap_or(3600),
-                ),
-            user_id: user.id,
-            user_icon: user.avatar.map(|s| {
-                if s.starts_with("_a") {
-                    s.strip_prefix("_a").unwrap().to_string()
-                } else {
-                    s
-                }
-            }),
-            username: user.username,
-            discriminator: user.discriminator,
-            guilds,
-        },
-    );
+        .push(session_id.clone(), logged_user);
 
     cookies.add_private(
-        rocket::http::Cookie::build("token", token.access_token().to_string())
+        rocket::http::Cookie::build(SESSION_COOKIE, session_id)
             .same_site(rocket::http::SameSite::Lax)
-            .expires(
-                Some(
-                    SystemTime::now()
-                        + std::time::Duration::from_secs(
-                            token
-                                .expires_in()
-                                .map(|s| s.max(0) as u64)
-                                .unwrap_or(u64::MAX),
-                        ),
-                )
-                .map(rocket::time::OffsetDateTime::from),
-            )
+            .http_only(true)
+            .expires(rocket::time::OffsetDateTime::from(
+                SystemTime::now() + std::time::Duration::from_secs(SESSION_TTL),
+            ))
             .finish(),
     );
     Ok(Redirect::to("/"))
 }
 
-#[get("/logout")]
-pub async fn logout(logic: &State<crate::discord::Logic>, cookies: &CookieJar<'_>) -> Json<bool> {
-    if let Some(token) = cookies.get_private("token") {
-        let user = logic.user_cache.write().await.pop_entry(token.value());
-        if let Some(user) = user {
-            logic
-                .user_id_to_token
-                .write()
+/// End the current session, or every session of the user when `everywhere` is set
+#[get("/logout?<everywhere>")]
+pub async fn logout(
+    logic: &State<crate::discord::Logic>,
+    cookies: &CookieJar<'_>,
+    mut con: crate::Connection<crate::DemDb>,
+    everywhere: Option<bool>,
+) -> Result<Json<bool>, rocket::http::Status> {
+    let cookie = match cookies.get_private(SESSION_COOKIE) {
+        Some(c) => c,
+        None => return Ok(Json(false)),
+    };
+    cookies.remove_private(cookie.clone());
+    let id = cookie.value();
+    let redis_error = |e: rocket_db_pools::deadpool_redis::redis::RedisError| {
+        error!("Error when comunicating with redis db: {e}");
+        rocket::http::Status::InternalServerError
+    };
+
+    let mut sessions = vec![id.to_string()];
+    if everywhere.unwrap_or(false) {
+        if let Some(session) = Session::load(&mut con, id).await.map_err(redis_error)? {
+            let user_sessions = Session::user_sessions_key(session.user_id);
+            sessions = Cmd::smembers(&user_sessions)
+                .query_async::<_, Vec<String>>(&mut *con)
                 .await
-                .pop_entry(&user.1.user_id);
+                .map_err(redis_error)?;
+            if !sessions.iter().any(|s| s == id) {
+                sessions.push(id.to_string());
+            }
         }
-        cookies.remove_private(token);
-        return Json(true);
     }
-    Json(false)
+    for session in sessions {
+        Session::end(&mut con, logic, &session)
+            .await
+            .map_err(redis_error)?;
+    }
+    Ok(Json(true))
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum UserAuthError {
     NoToken,
     InvalidToken,
+    /// The API token doesn't have the scope needed by the route
+    MissingScope,
     InternalError,
 }
 
-#[derive(Clone, Debug, OpenApiFromRequest)]
+#[derive(Clone, Debug)]
 pub struct User {
-    pub token: String,
+    /// Opaque id of the session, the key of `Logic::user_cache`
+    pub session: String,
+    /// Discord access token of the user, must never be sent to the client.
+    /// `None` when authenticated with an API token
+    pub token: Option<String>,
+    /// Id of the API token used to authenticate, if any
+    pub api_token: Option<String>,
+}
+
+impl User {
+    /// Authenticate with an `Authorization: Bearer` API token
+    async fn from_api_token(
+        req: &Request<'_>,
+        con: &mut rocket_db_pools::deadpool_redis::Connection,
+        logic: &crate::discord::Logic,
+        token: &str,
+    ) -> request::Outcome<Self, UserAuthError> {
+        use crate::api_token::ApiToken;
+        let api_token = match ApiToken::load(con, token).await {
+            Ok(Some(t)) => t,
+            Ok(None) => {
+                return request::Outcome::Failure((
+                    rocket::http::Status::Unauthorized,
+                    UserAuthError::InvalidToken,
+                ))
+            }
+            Err(e) => {
+                error!("Error when comunicating with redis db: {e}");
+                return request::Outcome::Failure((
+                    rocket::http::Status::InternalServerError,
+                    UserAuthError::InternalError,
+                ));
+            }
+        };
+        if !api_token.allows(crate::api_token::required_scope(req)) {
+            return request::Outcome::Failure((
+                rocket::http::Status::Forbidden,
+                UserAuthError::MissingScope,
+            ));
+        }
+        // Guilds and permissions come from the cache as it is now, the scopes only restrict them
+        let session = ApiToken::cache_key(&api_token.id);
+        let logged_user = api_token.logged_user(logic).await;
+        logic
+            .user_cache
+            .write()
+            .await
+            .push(session.clone(), logged_user);
+        request::Outcome::Success(Self {
+            session,
+            token: None,
+            api_token: Some(api_token.id),
+        })
+    }
+
+    /// Refresh the access token of `session` if it is about to expire.
+    /// When another request is already refreshing it, wait for its result.
+    async fn refresh(
+        req: &Request<'_>,
+        con: &mut rocket_db_pools::deadpool_redis::Connection,
+        id: &str,
+        mut session: Session,
+    ) -> Result<Session, RefreshError> {
+        let now = unix_now();
+        if session.expires_at > now + REFRESH_MARGIN {
+            return Ok(session);
+        }
+        let expired = session.expires_at <= now;
+        let usable = |session: Session| (!expired).then_some(session).ok_or(RefreshError::Invalid);
+        let refresh_token = match &session.refresh_token {
+            Some(t) => t.clone(),
+            None => return usable(session),
+        };
+        let oauth2 = match req.guard::<rocket_oauth2::OAuth2<Discord>>().await {
+            request::Outcome::Success(o) => o,
+            _ => return usable(session),
+        };
+        // Discord invalidates a refresh token once used, only one request gets to refresh
+        let lock = format!("{}:refreshing", Session::key(id));
+        let locked = rocket_db_pools::deadpool_redis::redis::cmd("SET")
+            .arg(&lock)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(REFRESH_LOCK_SECS)
+            .query_async::<_, Option<String>>(&mut *con)
+            .await
+            .map(|r| r.is_some())
+            .unwrap_or(false);
+        if !locked {
+            return Self::wait_for_refresh(con, id, &lock, session).await;
+        }
+
+        let refreshed = match oauth2.refresh(&refresh_token).await {
+            Ok(token) => {
+                let mut refreshed = Session::new(session.user_id, &token);
+                refreshed.refresh_token = refreshed.refresh_token.or(session.refresh_token.take());
+                if let Err(e) = refreshed.save(con, id).await {
+                    error!("Error when comunicating with redis db: {e}");
+                }
+                Ok(refreshed)
+            }
+            Err(e) => {
+                warn!("Unable to refresh a Discord token: {e}");
+                usable(session)
+            }
+        };
+        let _ = Cmd::del(&lock).query_async::<_, ()>(&mut *con).await;
+        refreshed
+    }
+
+    /// Wait until the request holding `lock` is done refreshing the session `id`,
+    /// and use what it saved
+    async fn wait_for_refresh(
+        con: &mut rocket_db_pools::deadpool_redis::Connection,
+        id: &str,
+        lock: &str,
+        session: Session,
+    ) -> Result<Session, RefreshError> {
+        let step = std::time::Duration::from_millis(100);
+        let mut waited = std::time::Duration::ZERO;
+        loop {
+            tokio::time::sleep(step).await;
+            waited += step;
+            let refreshing = Cmd::exists(lock)
+                .query_async::<_, bool>(&mut *con)
+                .await
+                .unwrap_or(false);
+            if !refreshing {
+                break;
+            }
+            if waited >= REFRESH_WAIT {
+                return Err(RefreshError::Busy);
+            }
+        }
+        let now = unix_now();
+        match Session::load(con, id).await {
+            Ok(Some(s)) if s.expires_at > now => Ok(s),
+            Ok(_) => Err(RefreshError::Invalid),
+            Err(e) => {
+                error!("Error when comunicating with redis db: {e}");
+                (session.expires_at > now)
+                    .then_some(session)
+                    .ok_or(RefreshError::Busy)
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -153,106 +460,113 @@ impl<'r> FromRequest<'r> for User {
             .await
             .unwrap();
         let logic = req.guard::<&State<crate::discord::Logic>>().await.unwrap();
-        if let Some(token) = cookies.get_private("token") {
-            let mut lock = logic.user_cache.write().await;
-            let mut lock_id = logic.user_id_to_token.write().await;
-            let user = lock.get(token.value());
-            if let Some(_user) = user {
-                return request::Outcome::Success(Self {
-                    token: token.value().to_string(),
-                });
-            } else {
-                let res = match Cmd::get(token.value())
-                    .query_async::<_, Option<u64>>(&mut *con)
-                    .await
-                    .map_err(|e| error!("Error when comunicating with redis db: {e}"))
-                {
-                    Ok(o) => o,
-                    Err(_) => {
-                        return request::Outcome::Failure((
-                            rocket::http::Status::InternalServerError,
-                            UserAuthError::InternalError,
-                        ))
-                    }
-                };
-                if res.is_some() {
-                    let user = match logic.get_user(token.value()).await.map_err(|e| {
-                        error!("Error when communicating with the Discord API: {e}");
-                        rocket::http::Status::InternalServerError
-                    }) {
-                        Ok(o) => o,
-                        Err(e) => {
-                            error!("Error when comunicating with the Discord API: {e}");
-                            return request::Outcome::Failure((
-                                rocket::http::Status::InternalServerError,
-                                UserAuthError::InternalError,
-                            ));
-                        }
-                    };
-
-                    let guilds = match logic
-                        .get_guilds_of_client_with_permission(token.value())
-                        .await
-                    {
-                        Ok(g) => g,
-                        Err(e) => {
-                            error!("Error when comunicating with Discord API: {e}");
-                            return request::Outcome::Failure((
-                                rocket::http::Status::InternalServerError,
-                                UserAuthError::InternalError,
-                            ));
-                        }
-                    };
-
-                    lock_id.push(user.id, token.value().to_string());
-                    lock.push(
-                        token.value().to_string(),
-                        crate::discord::LoggedUser {
-                            expires_at: SystemTime::now()
-                                + std::time::Duration::from_secs(
-                                    token
-                                        .expires_datetime()
-                                        .map(std::time::SystemTime::from)
-                                        .map(|t| {
-                                            match t
-                                                .elapsed()
-                                                .map(|_| 0)
-                                                .map_err(|e| e.duration().as_secs())
-                                            {
-                                                Ok(e) | Err(e) => e,
-                                            }
-                                        })
-                                        .map(|s| s.max(0) as u64)
-                                        .unwrap_or(0),
-                                ),
-                            user_id: user.id,
-                            user_icon: user.avatar.map(|s| {
-                                if s.starts_with("_a") {
-                                    s.strip_prefix("_a").unwrap().to_string()
-                                } else {
-                                    s
-                                }
-                            }),
-                            username: user.username,
-                            discriminator: user.discriminator,
-                            guilds,
-                        },
-                    );
-                    return request::Outcome::Success(Self {
-                        token: token.value().to_string(),
-                    });
-                } else {
+        if let Some(token) = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+        {
+            return Self::from_api_token(req, &mut con, logic, token.trim()).await;
+        }
+        let id = match cookies.get_private(SESSION_COOKIE) {
+            Some(c) => c.value().to_string(),
+            None => {
+                return request::Outcome::Failure((
+                    rocket::http::Status::Unauthorized,
+                    UserAuthError::NoToken,
+                ))
+            }
+        };
+
+        let session = match Session::load(&mut con, &id).await {
+            Ok(Some(s)) => s,
+            Ok(None) => {
+                return request::Outcome::Failure((
+                    rocket::http::Status::BadRequest,
+                    UserAuthError::InvalidToken,
+                ))
+            }
+            Err(e) => {
+                error!("Error when comunicating with redis db: {e}");
+                return request::Outcome::Failure((
+                    rocket::http::Status::InternalServerError,
+                    UserAuthError::InternalError,
+                ));
+            }
+        };
+        let session_user_id = session.user_id;
+        let session = match Self::refresh(req, &mut con, &id, session).await {
+            Ok(s) => s,
+            Err(RefreshError::Busy) => {
+                return request::Outcome::Failure((
+                    rocket::http::Status::ServiceUnavailable,
+                    UserAuthError::InternalError,
+                ))
+            }
+            Err(RefreshError::Invalid) => {
+                let _ = Cmd::del(Session::key(&id))
+                    .query_async::<_, ()>(&mut *con)
+                    .await;
+                let _ = Cmd::srem(Session::user_sessions_key(session_user_id), &id)
+                    .query_async::<_, ()>(&mut *con)
+                    .await;
+                return request::Outcome::Failure((
+                    rocket::http::Status::BadRequest,
+                    UserAuthError::InvalidToken,
+                ));
+            }
+        };
+
+        let cached = logic.user_cache.write().await.contains(&id);
+        if !cached {
+            let logged_user = match logged_user(logic, &session).await {
+                Ok(u) => u,
+                Err(e) => {
+                    error!("Error when comunicating with the Discord API: {e}");
                     return request::Outcome::Failure((
-                        rocket::http::Status::BadRequest,
-                        UserAuthError::InvalidToken,
+                        rocket::http::Status::InternalServerError,
+                        UserAuthError::InternalError,
                     ));
                 }
-            }
-        } else {
-            return request::Outcome::Failure((
-                rocket::http::Status::Unauthorized,
-                UserAuthError::NoToken,
-            ));
+            };
+            logic
+                .user_id_to_session
+                .write()
+                .await
+                .push(logged_user.user_id, id.clone());
+            logic.user_cache.write().await.push(id.clone(), logged_user);
         }
+        request::Outcome::Success(Self {
+            session: id,
+            token: Some(session.access_token),
+            api_token: None,
+        })
+    }
+}
+
+impl<'r> rocket_okapi::request::OpenApiFromRequest<'r> for User {
+    fn from_request_input(
+        _gen: &mut rocket_okapi::gen::OpenApiGenerator,
+        _name: String,
+        _required: bool,
+    ) -> rocket_okapi::Result<rocket_okapi::request::RequestHeaderInput> {
+        use okapi::openapi3::{Object, SecurityRequirement, SecurityScheme, SecuritySchemeData};
+        let scheme = SecurityScheme {
+            description: Some(
+                "API token created with `POST /api/tokens`, the session cookie of the webapp works too"
+                    .to_string(),
+            ),
+            data: SecuritySchemeData::Http {
+                scheme: "bearer".to_string(),
+                bearer_format: Some("DEM API token".to_string()),
+            },
+            extensions: Object::default(),
+        };
+        let mut requirement = SecurityRequirement::new();
+        requirement.insert("ApiToken".to_string(), Vec::new());
+        Ok(rocket_okapi::request::RequestHeaderInput::Security(
+            "ApiToken".to_string(),
+            scheme,
+            requirement,
+        ))
     }
 }
@@ -0,0 +1,522 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Where the image store keeps its files.
+/// Keys are `/` separated paths, like `<guildid>/<uuid>.json`.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, data: Vec<u8>) -> std::io::Result<()>;
+    /// Fails with `ErrorKind::NotFound` for unknown keys
+    async fn get(&self, key: &str) -> std::io::Result<Vec<u8>>;
+    /// Deleting an unknown key isn't an error
+    async fn delete(&self, key: &str) -> std::io::Result<()>;
+    /// Every key under the `<prefix>/` directory and its subdirectories,
+    /// every key of the store for an empty prefix
+    async fn list(&self, prefix: &str) -> std::io::Result<Vec<String>>;
+}
+
+pub struct FsBlobStore {
+    base_path: std::path::PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(base_path: std::path::PathBuf) -> Self {
+        FsBlobStore { base_path }
+    }
+
+    fn path(&self, key: &str) -> std::path::PathBuf {
+        let mut p = self.base_path.clone();
+        p.extend(key.split('/'));
+        p
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> std::io::Result<()> {
+        let path = self.path(key);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(path, data).await
+    }
+
+    async fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        tokio::fs::read(self.path(key)).await
+    }
+
+    async fn delete(&self, key: &str) -> std::io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut dirs = vec![prefix.to_string()];
+        while let Some(dir_key) = dirs.pop() {
+            let mut dir = match tokio::fs::read_dir(self.path(&dir_key)).await {
+                Ok(d) => d,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            while let Some(entry) = dir.next_entry().await? {
+                let name = match entry.file_name().to_str() {
+                    Some(name) => name.to_string(),
+                    None => continue,
+                };
+                let key = match dir_key.as_str() {
+                    "" => name,
+                    dir_key => format!("{dir_key}/{name}"),
+                };
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(key);
+                } else {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Any S3 compatible service, requests are signed with AWS Signature Version 4
+/// and use path-style URLs so MinIO-like servers work out of the box
+pub struct S3BlobStore {
+    client: reqwest::Client,
+    endpoint: reqwest::Url,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "S3Config::default_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl S3Config {
+    fn default_region() -> String {
+        "us-east-1".to_string()
+    }
+}
+
+fn io_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e)
+}
+
+/// Percent-encode everything but unreserved characters, and `/` when `keep_slash` is set
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// A page of `ListObjectsV2`, only what `list` needs
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(default)]
+    contents: Vec<ListedObject>,
+    #[serde(default)]
+    is_truncated: bool,
+    next_continuation_token: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedObject {
+    key: String,
+}
+
+impl S3BlobStore {
+    pub fn new(config: S3Config) -> Result<Self, rocket::figment::Error> {
+        let endpoint = reqwest::Url::parse(&config.endpoint).map_err(|e| {
+            rocket::figment::Error::from(format!("dem.storage.endpoint is not a valid url: {e}"))
+        })?;
+        Ok(S3BlobStore {
+            client: reqwest::Client::new(),
+            endpoint,
+            bucket: config.bucket,
+            region: config.region,
+            access_key: config.access_key,
+            secret_key: config.secret_key,
+        })
+    }
+
+    /// Build a signed request for `key` in the bucket, `query` must be sorted by name
+    fn request(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(&str, &str)],
+        payload: Vec<u8>,
+    ) -> reqwest::RequestBuilder {
+        let now = rocket::time::OffsetDateTime::now_utc();
+        let date = format!(
+            "{:04}{:02}{:02}",
+            now.year(),
+            u8::from(now.month()),
+            now.day()
+        );
+        let amz_date = format!(
+            "{date}T{:02}{:02}{:02}Z",
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+
+        let base_path = self.endpoint.path().trim_end_matches('/');
+        let path = match key {
+            "" => format!("{base_path}/{}", self.bucket),
+            key => format!("{base_path}/{}/{key}", self.bucket),
+        };
+        let path = uri_encode(&path, true);
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k, false), uri_encode(v, false)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let host = match (self.endpoint.host_str(), self.endpoint.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => String::new(),
+        };
+        let payload_hash = hex::encode(Sha256::digest(&payload));
+
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key_date = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        let key_region = hmac(&key_date, &self.region);
+        let key_service = hmac(&key_region, "s3");
+        let key_signing = hmac(&key_service, "aws4_request");
+        let signature = hex::encode(hmac(&key_signing, &string_to_sign));
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        url.set_query((!query.is_empty()).then_some(query.as_str()));
+        self.client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+                    self.access_key
+                ),
+            )
+            .body(payload)
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> std::io::Result<reqwest::Response> {
+        let response = request.send().await.map_err(io_error)?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(std::io::ErrorKind::NotFound.into());
+        }
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(io_error(format!("S3 returned {status}: {body}")));
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> std::io::Result<()> {
+        self.send(self.request(reqwest::Method::PUT, key, &[], data))
+            .await
+            .map(|_| ())
+    }
+
+    async fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        let response = self
+            .send(self.request(reqwest::Method::GET, key, &[], Vec::new()))
+            .await?;
+        Ok(response.bytes().await.map_err(io_error)?.to_vec())
+    }
+
+    async fn delete(&self, key: &str) -> std::io::Result<()> {
+        match self
+            .send(self.request(reqwest::Method::DELETE, key, &[], Vec::new()))
+            .await
+        {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let prefix = match prefix {
+            "" => String::new(),
+            prefix => format!("{prefix}/"),
+        };
+        let mut keys = Vec::new();
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let mut query = vec![];
+            if let Some(token) = &continuation_token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            query.push(("list-type", "2"));
+            query.push(("prefix", prefix.as_str()));
+            let body = self
+                .send(self.request(reqwest::Method::GET, "", &query, Vec::new()))
+                .await?
+                .text()
+                .await
+                .map_err(io_error)?;
+            let page: ListBucketResult = quick_xml::de::from_str(&body).map_err(io_error)?;
+            keys.extend(page.contents.into_iter().map(|o| o.key));
+            continuation_token = match page.next_continuation_token {
+                Some(token) if page.is_truncated => Some(token),
+                _ => break Ok(keys),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Keys listed per page by the stand-in, small to exercise pagination
+    const PAGE_SIZE: usize = 2;
+
+    type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+    fn percent_decode(s: &str) -> String {
+        let bytes = s.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match (bytes[i], s.get(i + 1..i + 3)) {
+                (b'%', Some(hex)) => {
+                    decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                    i += 3;
+                }
+                (b, _) => {
+                    decoded.push(b);
+                    i += 1;
+                }
+            }
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    fn xml_escape(s: &str) -> String {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    /// Read a request, returns its method, target, lowercased headers and body
+    async fn read_request(
+        stream: &mut tokio::net::TcpStream,
+    ) -> Option<(String, String, String, Vec<u8>)> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        let end = loop {
+            let n = stream.read(&mut buf).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            data.extend_from_slice(&buf[..n]);
+            if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end;
+            }
+        };
+        let head = String::from_utf8_lossy(&data[..end]).to_string();
+        let (request_line, headers) = head.split_once("\r\n").unwrap_or((&head, ""));
+        let mut request_line = request_line.split_whitespace();
+        let method = request_line.next()?.to_string();
+        let target = request_line.next()?.to_string();
+        let headers = headers.to_lowercase();
+        let length = headers
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length:"))
+            .and_then(|l| l.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        let mut body = data[end + 4..].to_vec();
+        while body.len() < length {
+            let n = stream.read(&mut buf).await.ok()?;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..n]);
+        }
+        Some((method, target, headers, body))
+    }
+
+    fn list_page(objects: &Objects, url: &reqwest::Url) -> String {
+        let query: BTreeMap<_, _> = url.query_pairs().into_owned().collect();
+        let prefix = query.get("prefix").cloned().unwrap_or_default();
+        let start = query
+            .get("continuation-token")
+            .map(|t| t.strip_prefix("next&").unwrap().parse::<usize>().unwrap())
+            .unwrap_or(0);
+        let keys = objects
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|k| k.starts_with(&prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        let page = keys.iter().skip(start).take(PAGE_SIZE);
+        let truncated = start + PAGE_SIZE < keys.len();
+        let mut body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+             <Name>dem</Name><Prefix>{}</Prefix><MaxKeys>{PAGE_SIZE}</MaxKeys>\
+             <IsTruncated>{truncated}</IsTruncated>",
+            xml_escape(&prefix)
+        );
+        for key in page {
+            body.push_str(&format!(
+                "<Contents><Key>{}</Key><Size>1</Size></Contents>",
+                xml_escape(key)
+            ));
+        }
+        if truncated {
+            body.push_str(&format!(
+                "<NextContinuationToken>{}</NextContinuationToken>",
+                xml_escape(&format!("next&{}", start + PAGE_SIZE))
+            ));
+        }
+        body.push_str("</ListBucketResult>");
+        body
+    }
+
+    /// A MinIO-like stand-in keeping the objects of the `dem` bucket in memory.
+    /// Requests without a signature are refused.
+    async fn stand_in() -> (S3BlobStore, Objects) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let objects = Objects::default();
+        tokio::spawn({
+            let objects = objects.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let objects = objects.clone();
+                    tokio::spawn(async move {
+                        while let Some((method, target, headers, body)) =
+                            read_request(&mut stream).await
+                        {
+                            let url = reqwest::Url::parse(&format!("http://s3{target}")).unwrap();
+                            let key = percent_decode(url.path())
+                                .strip_prefix("/dem")
+                                .unwrap()
+                                .trim_start_matches('/')
+                                .to_string();
+                            let signed = headers
+                                .contains("authorization: aws4-hmac-sha256 credential=access/")
+                                && headers.contains("x-amz-content-sha256:");
+                            let (status, body) = match (method.as_str(), key.as_str()) {
+                                _ if !signed => ("403 Forbidden", Vec::new()),
+                                ("GET", "") => ("200 OK", list_page(&objects, &url).into_bytes()),
+                                ("PUT", key) => {
+                                    objects.lock().unwrap().insert(key.to_string(), body);
+                                    ("200 OK", Vec::new())
+                                }
+                                ("GET", key) => match objects.lock().unwrap().get(key) {
+                                    Some(data) => ("200 OK", data.clone()),
+                                    None => ("404 Not Found", Vec::new()),
+                                },
+                                ("DELETE", key) => {
+                                    objects.lock().unwrap().remove(key);
+                                    ("204 No Content", Vec::new())
+                                }
+                                _ => ("405 Method Not Allowed", Vec::new()),
+                            };
+                            let head = format!(
+                                "HTTP/1.1 {status}\r\ncontent-length: {}\r\n\r\n",
+                                body.len()
+                            );
+                            if stream.write_all(head.as_bytes()).await.is_err()
+                                || stream.write_all(&body).await.is_err()
+                            {
+                                return;
+                            }
+                        }
+                    });
+                }
+            }
+        });
+        let store = S3BlobStore::new(S3Config {
+            endpoint,
+            bucket: "dem".to_string(),
+            region: S3Config::default_region(),
+            access_key: "access".to_string(),
+            secret_key: "secret".to_string(),
+        })
+        .unwrap();
+        (store, objects)
+    }
+
+    #[tokio::test]
+    async fn put_get_delete() {
+        let (store, objects) = stand_in().await;
+        store.put("1/image.json", b"{}".to_vec()).await.unwrap();
+        assert_eq!(objects.lock().unwrap().get("1/image.json").unwrap(), b"{}");
+        assert_eq!(store.get("1/image.json").await.unwrap(), b"{}");
+        store.delete("1/image.json").await.unwrap();
+        let missing = store.get("1/image.json").await.unwrap_err();
+        assert_eq!(missing.kind(), std::io::ErrorKind::NotFound);
+        store.delete("1/image.json").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn list_follows_continuation_tokens() {
+        let (store, _) = stand_in().await;
+        let keys = [
+            "1/a.json",
+            "1/b&c.json",
+            "1/d<e>.json",
+            "1/f.json",
+            "1/g/h.json",
+        ];
+        for key in keys {
+            store.put(key, Vec::new()).await.unwrap();
+        }
+        store.put("2/a.json", Vec::new()).await.unwrap();
+
+        let mut listed = store.list("1").await.unwrap();
+        listed.sort();
+        assert_eq!(listed, keys);
+        assert_eq!(store.list("").await.unwrap().len(), keys.len() + 1);
+        assert!(store.list("3").await.unwrap().is_empty());
+    }
+}
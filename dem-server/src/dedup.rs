@@ -0,0 +1,97 @@
+use futures::StreamExt;
+use image::{imageops::FilterType, ImageFormat, RgbaImage};
+
+use dem_types::discord as types;
+
+/// Images at least this similar are reported as duplicates
+pub const SIMILARITY_THRESHOLD: f32 = 0.9;
+/// How many frames of an animation are hashed, the first one included
+const SAMPLED_FRAMES: usize = 4;
+/// Emojis fetched from the CDN at the same time
+const CONCURRENT_FETCHES: usize = 8;
+
+/// Difference hash of every sampled frame, `None` for formats we can't decode
+/// or images above the limits of `crate::processing`
+pub fn hash_image(bytes: &[u8]) -> Option<Vec<u64>> {
+    match image::guess_format(bytes).ok()? {
+        ImageFormat::Gif => {
+            let frames = crate::processing::decode_gif(bytes).ok()?;
+            let step = (frames.len() / SAMPLED_FRAMES).max(1);
+            Some(
+                frames
+                    .iter()
+                    .step_by(step)
+                    .take(SAMPLED_FRAMES)
+                    .map(|f| dhash(f.buffer()))
+                    .collect(),
+            )
+        }
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP => {
+            Some(vec![dhash(&crate::processing::decode_static(bytes).ok()?)])
+        }
+        _ => None,
+    }
+}
+
+/// 64 bits telling whether each pixel of a 9x8 grayscale thumbnail
+/// is darker than its right neighbour
+fn dhash(image: &RgbaImage) -> u64 {
+    let small = image::imageops::resize(image, 9, 8, FilterType::Triangle);
+    // Transparent pixels are seen over a white background
+    let luma = |x: u32, y: u32| {
+        let [r, g, b, a] = small.get_pixel(x, y).0;
+        let l = (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000;
+        (l * a as u32 + 255 * (255 - a as u32)) / 255
+    };
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash = (hash << 1) | (luma(x, y) < luma(x + 1, y)) as u64;
+        }
+    }
+    hash
+}
+
+/// Similarity of the closest pair of frames, from `0.0` to `1.0`
+pub fn similarity(a: &[u64], b: &[u64]) -> f32 {
+    a.iter()
+        .flat_map(|a| b.iter().map(move |b| (a ^ b).count_ones()))
+        .min()
+        .map(|distance| 1.0 - distance as f32 / 64.0)
+        .unwrap_or_default()
+}
+
+/// Hashes of the guild's live emojis, fetched from the CDN the first time they're seen
+pub async fn live_emoji_hashes(
+    logic: &crate::discord::Logic,
+    guildid: u64,
+) -> Vec<(types::EmojiItem, Vec<u64>)> {
+    let emojis = logic
+        .get_guild(guildid)
+        .map(|g| g.emojis.clone())
+        .unwrap_or_default();
+    futures::stream::iter(emojis)
+        .map(|emoji| async move {
+            if let Some(hashes) = logic.emoji_hashes.get(&emoji.id) {
+                return (emoji, hashes.clone());
+            }
+            let bytes = match logic.fetch_asset(&emoji.cdn_url()).await {
+                Ok(b) => b,
+                Err(e) => {
+                    warn!("Error when fetching emoji {} for hashing: {e}", emoji.id);
+                    return (emoji, Vec::new());
+                }
+            };
+            let hashes = tokio::task::spawn_blocking(move || hash_image(&bytes))
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+            // Emoji images never change, the id is enough to cache them
+            logic.emoji_hashes.insert(emoji.id, hashes.clone());
+            (emoji, hashes)
+        })
+        .buffer_unordered(CONCURRENT_FETCHES)
+        .collect()
+        .await
+}
@@ -5,10 +5,9 @@ use rocket::figment::Figment;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-const GOOGLE_SAFESAERCH_URL: &str = "https://vision.googleapis.com/v1/images:annotate";
 const DISCORD_API: &str = "https://discord.com/api/v10";
-const DISCORD_WS: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
-const INTENTS: u64 = (1 << 0) | (1 << 3);
+
+pub use types::MANAGE_EMOJIS_AND_STICKERS;
 
 static mut BOT_AUTH_HEADER: &str = "";
 
@@ -18,256 +17,20 @@ pub fn get_token() -> &'static str {
 
 pub struct Logic {
     discord_token: String,
-    google_token: String,
-    #[cfg(feature = "google_api_remote")]
-    external_url: String,
-    #[cfg(not(feature = "google_api_remote"))]
-    tmp_dir: String,
+    /// Credentials of the OAuth2 application, needed to revoke user tokens
+    oauth_client: OAuthClient,
     pub guilds: &'static dashmap::DashMap<u64, types::PartialGuild, fxhash::FxBuildHasher>,
+    /// Perceptual hashes of live emojis, keyed by emoji id
+    pub emoji_hashes: dashmap::DashMap<u64, Vec<u64>, fxhash::FxBuildHasher>,
     pub user_cache: std::sync::Arc<tokio::sync::RwLock<lru::LruCache<String, LoggedUser>>>,
-    pub user_id_to_token:
+    pub user_id_to_session:
         std::sync::Arc<tokio::sync::RwLock<lru::LruCache<u64, String, fxhash::FxBuildHasher>>>,
     client: reqwest_middleware::ClientWithMiddleware,
+    pub moderation: crate::moderation::Moderation,
+    gateway_shutdown: std::sync::Arc<tokio::sync::Notify>,
 }
 
 impl Logic {
-    async fn handle_gateway(
-        token: String,
-        guilds: &'static dashmap::DashMap<u64, types::PartialGuild, fxhash::FxBuildHasher>,
-        user_cache: std::sync::Arc<tokio::sync::RwLock<lru::LruCache<String, LoggedUser>>>,
-        user_id_to_token: std::sync::Arc<
-            tokio::sync::RwLock<lru::LruCache<u64, String, fxhash::FxBuildHasher>>,
-        >,
-    ) {
-        use futures_util::{sink::SinkExt, stream::StreamExt};
-        use rand::{Rng, SeedableRng};
-
-        let (client_writer, mut client_reader) =
-            match tokio_tungstenite::connect_async(DISCORD_WS).await {
-                Err(e) => {
-                    error!("Error when connecting to discord gateway: {e}");
-                    return;
-                }
-                Ok((ws, _)) => ws.split(),
-            };
-
-        static ACTION_ID: std::sync::atomic::AtomicI64 = std::sync::atomic::AtomicI64::new(-1);
-        let mut session_id: Option<String> = None;
-        let client_writer = std::sync::Arc::new(tokio::sync::Mutex::new(client_writer));
-
-        let notify = std::sync::Arc::new(tokio::sync::Notify::new());
-        let notify2 = notify.clone();
-
-        while let Some(message) = client_reader.next().await {
-            match message {
-                Err(e) => {
-                    error!("Error with the gateway connection: {e}");
-                    if let Some(session_id) = &session_id {
-                        let mut client_writer_lock = client_writer.lock().await;
-                        let (client_writer_new, client_reader_new) =
-                            match tokio_tungstenite::connect_async(DISCORD_WS).await {
-                                Err(e) => {
-                                    error!("Error when connecting to discord gateway: {e}");
-                                    return;
-                                }
-                                Ok((ws, _)) => ws.split(),
-                            };
-                        client_reader = client_reader_new;
-                        *client_writer_lock = client_writer_new;
-                        match client_writer_lock
-                            .send(tokio_tungstenite::tungstenite::Message::Binary(
-                                serde_json::to_vec(&json!({
-                                    "op": 6,
-                                        "d": {
-                                        "token": token.as_str(),
-                                        "session_id": session_id.as_str(),
-                                        "seq": ACTION_ID.load(std::sync::atomic::Ordering::Relaxed)
-                                    }
-                                }))
-                                .unwrap(),
-                            ))
-                            .await
-                        {
-                            Ok(_) => info!("[WS] Sent OPCODE 6"),
-                            Err(e) => {
-                                error!("[WS] Error when sending gateway: {e}: OPCODE 6")
-                            }
-                        };
-                    }
-                }
-                Ok(m) => {
-                    trace!("[WS] Got message");
-                    let m = serde_json::from_str::<serde_json::Value>({
-                        let m = m.to_text().unwrap();
-                        if !m.is_empty() {
-                            m
-                        } else {
-                            debug!("[WS] Skipped Message");
-                            continue;
-                        }
-                    })
-                    .unwrap();
-
-                    match m["op"].as_u64().unwrap_or(0) {
-                        0 => {
-                            match m["t"].as_str().unwrap() {
-                                "READY" => {
-                                    info!("Connected to discord gateway!");
-                                    session_id =
-                                        m["d"]["session_id"].as_str().map(|s| s.to_string());
-                                }
-                                "GUILD_CREATE" => {
-                                    debug!("Got GUILD_CREATE");
-                                    let guild = match serde_json::from_value::<types::PartialGuild>(
-                                        m["d"].clone(),
-                                    ) {
-                                        Err(e) => {
-                                            error!("Error while parsing GUILD_CREATE event: {e:?}");
-                                            continue;
-                                        }
-                                        Ok(g) => g,
-                                    };
-
-                                    guilds.insert(guild.id, guild.clone());
-                                    tokio::spawn({
-                                        let user_id_to_token = user_id_to_token.clone();
-                                        let user_cache = user_cache.clone();
-                                        async move {
-                                            let mut lock = user_id_to_token.write().await;
-                                            let mut lock_cache = user_cache.write().await;
-                                            for member in guild.members {
-                                                if let Some(token) = lock.get(&member.user.id) {
-                                                    let v = lock_cache.get_mut(token).unwrap();
-                                                    v.guilds.insert(guild.id, member.permissions);
-                                                }
-                                            }
-                                        }
-                                    });
-                                }
-                                "GUILD_DELETE" => {
-                                    debug!("Got GUILD_DELETE");
-                                    #[derive(serde::Deserialize)]
-                                    struct GuildUnavailable {
-                                        #[serde(deserialize_with = "deserialize_str")]
-                                        id: u64,
-                                        // unavailable: Option<bool>,
-                                    }
-                                    let guild = match serde_json::from_value::<GuildUnavailable>(
-                                        m["d"].clone(),
-                                    ) {
-                                        Err(e) => {
-                                            error!("Error while parsing GUILD_DELETE event: {e:?}");
-                                            continue;
-                                        }
-                                        Ok(g) => g,
-                                    };
-                                    guilds.remove(&guild.id);
-                                }
-                                "" => {}
-                                event_name => trace!("Unhandled event: {event_name}"),
-                            };
-
-                            ACTION_ID.store(
-                                m["s"].as_i64().unwrap(),
-                                std::sync::atomic::Ordering::Relaxed,
-                            );
-                        }
-                        10 => {
-                            let token = token.clone();
-                            let notify = notify.clone();
-                            trace!("[WS] Hello from gateway");
-                            let interval = m["d"]["heartbeat_interval"].as_u64().unwrap() as f64;
-                            tokio::spawn({
-                                let client = client_writer.clone();
-                                let mut rng = rand::rngs::StdRng::from_entropy();
-                                async move {
-                                    let mut client_lock = client.lock().await;
-                                    trace!("[WS] Sending first heartbeat");
-                                    match client_lock
-                                        .send(tokio_tungstenite::tungstenite::Message::Binary(
-                                            serde_json::to_vec(&json!({
-                                                "op": 1,
-                                                "d": null,
-                                            }))
-                                            .unwrap(),
-                                        ))
-                                        .await
-                                    {
-                                        Ok(_) => trace!("[WS] Sent OPCODE 1"),
-                                        Err(e) => {
-                                            error!("[WS] Error when sending gateway: {e}")
-                                        }
-                                    };
-                                    trace!("[WS] Sending OPCODE 2");
-                                    match client_lock
-                                        .send(tokio_tungstenite::tungstenite::Message::Binary(
-                                            serde_json::to_vec(&json!({
-                                                "op": 2,
-                                                "d": {
-                                                    "token": token,
-                                                    "intents": INTENTS,
-                                                    "properties": {
-                                                        "os": "linux",
-                                                        "browser": "dem.maix.me",
-                                                        "device": "dem.maix.me"
-                                                    }
-                                                },
-                                            }))
-                                            .unwrap(),
-                                        ))
-                                        .await
-                                    {
-                                        Ok(_) => debug!("[WS] Sent OPCODE 2"),
-                                        Err(e) => {
-                                            error!("[WS] Error when sending gateway: {e}: OPCODE 2")
-                                        }
-                                    };
-
-                                    drop(client_lock);
-
-                                    loop {
-                                        tokio::select!(
-                                            _ = tokio::time::sleep(std::time::Duration::from_millis(
-                                            (interval * rng.gen::<f64>()) as u64,
-                                        )) => {},
-                                                _ = notify.notified() => {}
-                                        );
-                                        match client.lock().await
-                                                .send(
-                                                    tokio_tungstenite::tungstenite::Message::Binary(
-                                                        serde_json::to_vec(&json!({
-                                                            "op": 1,
-                                                            "d": ACTION_ID.load(std::sync::atomic::Ordering::Relaxed),
-                                                        }))
-                                                        .unwrap(),
-                                                    ),
-                                                )
-                                                .await
-                                            {
-                                                Ok(_) => {}
-                                                Err(e) => {
-                                                    error!("[WS] Error when sending gateway: {e}")
-                                                }
-                                            };
-                                    }
-                                }
-                            });
-                        }
-                        1 => {
-                            trace!("[WS] Force heartbeat");
-                            notify2.notify_one();
-                        }
-                        11 => trace!("[WS] Heartbeat ACK"),
-
-                        n => {
-                            debug!("[WS] Got OPCODE '{n}'")
-                        }
-                    }
-                }
-            };
-        }
-    }
-
     pub async fn clear_logged_user_bg_task(
         timeout_ms: u64,
         cache: std::sync::Arc<tokio::sync::RwLock<lru::LruCache<String, LoggedUser>>>,
@@ -284,8 +47,8 @@ impl Logic {
                 .iter()
                 .filter_map(|(k, v)| v.expires_at.elapsed().ok().map(|_| (k.clone(), v.user_id)))
                 .collect::<Vec<_>>();
-            for (token, user_id) in to_prune {
-                cache_lock.pop(&token);
+            for (session, user_id) in to_prune {
+                cache_lock.pop(&session);
                 cache_id_lock.pop(&user_id);
             }
         }
@@ -297,11 +60,9 @@ impl Logic {
             discord_token: String,
             logged_user_cache: usize,
             logged_user_purge_time: u64,
-            google_token: String,
-            #[cfg(feature = "google_api_remote")]
-            external_url: String,
         }
         let config = figment.extract_inner::<Config>("dem")?;
+        let oauth_client = figment.extract_inner::<OAuthClient>("oauth.discord")?;
         unsafe {
             BOT_AUTH_HEADER = Box::leak(format!("BOT {}", config.discord_token).into_boxed_str());
         }
@@ -312,40 +73,47 @@ impl Logic {
         let user_cache = std::sync::Arc::new(tokio::sync::RwLock::new(lru::LruCache::new(
             config.logged_user_cache,
         )));
-        let user_id_to_token = std::sync::Arc::new(tokio::sync::RwLock::new(
+        let user_id_to_session = std::sync::Arc::new(tokio::sync::RwLock::new(
             lru::LruCache::with_hasher(config.logged_user_cache, fxhash::FxBuildHasher::default()),
         ));
 
-        tokio::spawn(Self::handle_gateway(
+        let gateway = crate::gateway::GatewayClient::new(
             config.discord_token.clone(),
-            guilds,
-            user_cache.clone(),
-            user_id_to_token.clone(),
+            crate::gateway::INTENTS,
+        );
+        let gateway_shutdown = gateway.shutdown_handle();
+        tokio::spawn(crate::gateway::dispatch(
+            gateway.events(),
+            vec![Box::new(crate::gateway::GuildCache(guilds))],
         ));
         tokio::spawn(Self::clear_logged_user_bg_task(
             config.logged_user_purge_time,
             user_cache.clone(),
-            user_id_to_token.clone(),
+            user_id_to_session.clone(),
         ));
         Ok(Self {
-            google_token: config.google_token,
             user_cache,
-            user_id_to_token,
+            user_id_to_session,
             guilds,
-            #[cfg(not(feature = "google_api_remote"))]
-            tmp_dir: figment.extract_inner("temp_dir").unwrap(),
-            #[cfg(feature = "google_api_remote")]
-            external_url: config.external_url,
+            emoji_hashes: Default::default(),
+            gateway_shutdown,
+            moderation: crate::moderation::Moderation::from_figment(figment)?,
             discord_token: config.discord_token,
+            oauth_client,
             client: {
                 let client = reqwest::Client::new();
                 reqwest_middleware::ClientBuilder::new(client)
-                    .with(crate::retry_middleware::DiscordRateLimitMiddleware)
+                    .with(crate::retry_middleware::DiscordRateLimitMiddleware::default())
                     .build()
             },
         })
     }
 
+    /// Close the gateway connection, the task won't reconnect after this
+    pub fn shutdown_gateway(&self) {
+        self.gateway_shutdown.notify_one();
+    }
+
     pub fn get_guild(
         &self,
         guildid: u64,
@@ -403,87 +171,252 @@ impl Logic {
             .map_err(Into::into)
     }
 
-    #[cfg(feature = "google_api_remote")]
-    pub async fn get_image_rating(
+    /// Revoke an access or refresh token of a user, `token_type_hint` is
+    /// either `access_token` or `refresh_token`
+    pub async fn revoke_token(
         &self,
-        img: &str,
-    ) -> Result<crate::image::ImageRating, Box<dyn std::error::Error + Send + Sync>> {
-        #[derive(Deserialize)]
-        struct Response {
-            response: crate::image::ImageRating,
+        token: &str,
+        token_type_hint: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let response = self
+            .client
+            .post(format!("{DISCORD_API}/oauth2/token/revoke"))
+            .basic_auth(
+                &self.oauth_client.client_id,
+                Some(&self.oauth_client.client_secret),
+            )
+            .form(&[("token", token), ("token_type_hint", token_type_hint)])
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Discord returned {status}: {body}").into());
         }
-        self.client
-            .post(GOOGLE_SAFESAERCH_URL)
-            .bearer_auth(&self.google_token)
-            .json(&json! {
-                {
-                  "requests": [
-                    {
-                      "image": {
-                        "source": {
-                          "imageUri": format!("{}/tmp/{img}", self.external_url)
-                        }
-                      },
-                      "features": [
-                        {
-                          "type": "SAFE_SEARCH_DETECTION"
-                        }
-                      ]
-                    }
-                  ]
-                }
-            })
+        Ok(())
+    }
+
+    pub async fn create_guild_emoji(
+        &self,
+        guild_id: u64,
+        name: &str,
+        content_type: &rocket::http::ContentType,
+        image: &[u8],
+    ) -> Result<types::EmojiItem, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self
+            .client
+            .post(format!("{DISCORD_API}/guilds/{guild_id}/emojis"))
+            .header("Authorization", get_token())
+            .json(&json!({
+                "name": name,
+                "image": format!("data:{content_type};base64,{}", base64::encode(image)),
+                "roles": [],
+            }))
             .send()
-            .await?
-            .json::<Response>()
-            .await
-            .map(|v| v.response)
-            .map_err(Into::into)
+            .await?;
+        discord_json(response).await
     }
 
-    #[cfg(not(feature = "google_api_remote"))]
-    pub async fn get_image_rating(
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_guild_sticker(
         &self,
-        img: &str,
-    ) -> Result<crate::image::ImageRating, Box<dyn std::error::Error + Send + Sync>> {
-        let encoded = base64::encode(
-            tokio::fs::read(format!("{}/{img}", self.tmp_dir))
-                .await
-                .unwrap(),
-        );
+        guild_id: u64,
+        name: &str,
+        description: &str,
+        tags: &str,
+        file_name: &str,
+        content_type: &rocket::http::ContentType,
+        file: Vec<u8>,
+    ) -> Result<types::StickerItem, Box<dyn std::error::Error + Send + Sync>> {
+        let form = reqwest::multipart::Form::new()
+            .text("name", name.to_string())
+            .text("description", description.to_string())
+            .text("tags", tags.to_string())
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(file)
+                    .file_name(file_name.to_string())
+                    .mime_str(&content_type.to_string())?,
+            );
+        let response = self
+            .client
+            .post(format!("{DISCORD_API}/guilds/{guild_id}/stickers"))
+            .header("Authorization", get_token())
+            .multipart(form)
+            .send()
+            .await?;
+        discord_json(response).await
+    }
 
-        #[derive(Deserialize)]
-        struct Response {
-            response: crate::image::ImageRating,
+    pub async fn modify_guild_emoji(
+        &self,
+        guild_id: u64,
+        emoji_id: u64,
+        name: Option<&str>,
+        roles: Option<&[u64]>,
+    ) -> Result<types::EmojiItem, Box<dyn std::error::Error + Send + Sync>> {
+        let mut body = serde_json::Map::new();
+        if let Some(name) = name {
+            body.insert("name".to_string(), json!(name));
         }
-        self.client
-            .post(GOOGLE_SAFESAERCH_URL)
-            .bearer_auth(&self.google_token)
-            .json(&json! {
-                {
-                  "requests": [
-                    {
-                      "image": {
-                        "content": encoded,
-                      },
-                      "features": [
-                        {
-                          "type": "SAFE_SEARCH_DETECTION"
-                        }
-                      ]
-                    }
-                  ]
-                }
-            })
+        if let Some(roles) = roles {
+            body.insert(
+                "roles".to_string(),
+                json!(roles.iter().map(u64::to_string).collect::<Vec<_>>()),
+            );
+        }
+        let response = self
+            .client
+            .patch(format!("{DISCORD_API}/guilds/{guild_id}/emojis/{emoji_id}"))
+            .header("Authorization", get_token())
+            .json(&body)
             .send()
-            .await?
-            .json::<Response>()
+            .await?;
+        discord_json(response).await
+    }
+
+    pub async fn modify_guild_sticker(
+        &self,
+        guild_id: u64,
+        sticker_id: u64,
+        name: Option<&str>,
+        description: Option<&str>,
+        tags: Option<&str>,
+    ) -> Result<types::StickerItem, Box<dyn std::error::Error + Send + Sync>> {
+        let mut body = serde_json::Map::new();
+        if let Some(name) = name {
+            body.insert("name".to_string(), json!(name));
+        }
+        if let Some(description) = description {
+            body.insert("description".to_string(), json!(description));
+        }
+        if let Some(tags) = tags {
+            body.insert("tags".to_string(), json!(tags));
+        }
+        let response = self
+            .client
+            .patch(format!(
+                "{DISCORD_API}/guilds/{guild_id}/stickers/{sticker_id}"
+            ))
+            .header("Authorization", get_token())
+            .json(&body)
+            .send()
+            .await?;
+        discord_json(response).await
+    }
+
+    pub async fn delete_guild_emoji(
+        &self,
+        guild_id: u64,
+        emoji_id: u64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let response = self
+            .client
+            .delete(format!("{DISCORD_API}/guilds/{guild_id}/emojis/{emoji_id}"))
+            .header("Authorization", get_token())
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Discord returned {status}: {body}").into());
+        }
+        Ok(())
+    }
+
+    pub async fn delete_guild_sticker(
+        &self,
+        guild_id: u64,
+        sticker_id: u64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let response = self
+            .client
+            .delete(format!(
+                "{DISCORD_API}/guilds/{guild_id}/stickers/{sticker_id}"
+            ))
+            .header("Authorization", get_token())
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Discord returned {status}: {body}").into());
+        }
+        Ok(())
+    }
+
+    /// Download an emoji or sticker asset from the Discord CDN
+    pub async fn fetch_asset(
+        &self,
+        url: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.client.get(url).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("CDN returned {status} for {url}").into());
+        }
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    pub async fn get_guild_member(
+        &self,
+        guild_id: u64,
+        user_id: u64,
+    ) -> Result<types::GuildMember, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self
+            .client
+            .get(format!("{DISCORD_API}/guilds/{guild_id}/members/{user_id}"))
+            .header("Authorization", get_token())
+            .send()
+            .await?;
+        discord_json(response).await
+    }
+
+    /// Effective permissions of a member computed from the gateway cache.
+    /// Members that the gateway didn't send yet are fetched and added to the cache.
+    pub async fn member_permissions(&self, guild_id: u64, user_id: u64) -> Option<u64> {
+        if let Some(p) = self.get_guild(guild_id)?.permissions_of(user_id) {
+            return Some(p);
+        }
+        let member = match self.get_guild_member(guild_id, user_id).await {
+            Ok(m) => m,
+            Err(e) => {
+                debug!("Unable to fetch member {user_id} of guild {guild_id}: {e}");
+                return None;
+            }
+        };
+        let mut guild = self.guilds.get_mut(&guild_id)?;
+        guild.upsert_member(member);
+        guild.permissions_of(user_id)
+    }
+
+    /// Check the permissions of a logged user in a guild
+    pub async fn user_has_permission(&self, session: &str, guild_id: u64, permission: u64) -> bool {
+        let user_id = match self
+            .user_cache
+            .write()
             .await
-            .map(|v| v.response)
-            .map_err(Into::into)
+            .get(session)
+            .filter(|u| u.guilds.contains_key(&guild_id))
+            .map(|u| u.user_id)
+        {
+            Some(id) => id,
+            None => return false,
+        };
+        self.member_permissions(guild_id, user_id)
+            .await
+            .map(|p| types::has_permission(p, permission))
+            .unwrap_or(false)
     }
 }
 
+/// The `oauth.discord` section shared with the `rocket_oauth2` fairing
+#[derive(Deserialize)]
+struct OAuthClient {
+    client_id: String,
+    client_secret: String,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DiscordUser {
     #[serde(deserialize_with = "deserialize_str")]
@@ -493,7 +426,20 @@ pub struct DiscordUser {
     pub avatar: Option<String>,
 }
 
-fn deserialize_str<'de, D>(deserializer: D) -> Result<u64, D::Error>
+/// Deserialize a Discord REST response, turning non-success statuses into an error
+/// that carries the body Discord sent back.
+async fn discord_json<T: serde::de::DeserializeOwned>(
+    response: reqwest::Response,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Discord returned {status}: {body}").into());
+    }
+    response.json().await.map_err(Into::into)
+}
+
+pub(crate) fn deserialize_str<'de, D>(deserializer: D) -> Result<u64, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
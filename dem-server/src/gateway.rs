@@ -0,0 +1,896 @@
+use dem_types::discord as types;
+use futures_util::{sink::SinkExt, stream::StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::discord::deserialize_str;
+
+const DISCORD_WS: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
+/// GUILDS, GUILD_MEMBERS and GUILD_EMOJIS_AND_STICKERS.
+/// GUILD_MEMBERS is privileged, it has to be enabled in the developer portal.
+pub const INTENTS: u64 = (1 << 0) | GUILD_MEMBERS | (1 << 3);
+/// Dropped from the intents when Discord refuses it
+const GUILD_MEMBERS: u64 = 1 << 1;
+
+type WsStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// A frame received from the gateway
+#[derive(Debug, Clone)]
+pub enum GatewayPayload {
+    Dispatch { seq: Option<i64>, event: Event },
+    Heartbeat,
+    Reconnect,
+    InvalidSession { resumable: bool },
+    Hello { heartbeat_interval: u64 },
+    HeartbeatAck,
+    Unknown(u64),
+}
+
+impl GatewayPayload {
+    pub fn parse(text: &str) -> Result<Self, serde_json::Error> {
+        #[derive(Deserialize)]
+        struct RawPayload {
+            op: u64,
+            #[serde(default)]
+            d: serde_json::Value,
+            s: Option<i64>,
+            t: Option<String>,
+        }
+        #[derive(Deserialize)]
+        struct Hello {
+            heartbeat_interval: u64,
+        }
+
+        let raw: RawPayload = serde_json::from_str(text)?;
+        Ok(match raw.op {
+            0 => GatewayPayload::Dispatch {
+                seq: raw.s,
+                event: Event::parse(raw.t.as_deref().unwrap_or_default(), raw.d),
+            },
+            1 => GatewayPayload::Heartbeat,
+            7 => GatewayPayload::Reconnect,
+            9 => GatewayPayload::InvalidSession {
+                resumable: raw.d.as_bool().unwrap_or(false),
+            },
+            10 => GatewayPayload::Hello {
+                heartbeat_interval: serde_json::from_value::<Hello>(raw.d)?.heartbeat_interval,
+            },
+            11 => GatewayPayload::HeartbeatAck,
+            op => GatewayPayload::Unknown(op),
+        })
+    }
+}
+
+/// Dispatched gateway events that DEM cares about
+#[derive(Debug, Clone)]
+pub enum Event {
+    Ready(Ready),
+    Resumed,
+    GuildCreate(Box<types::PartialGuild>),
+    GuildUpdate(GuildUpdate),
+    GuildDelete(GuildUnavailable),
+    GuildEmojisUpdate(GuildEmojisUpdate),
+    GuildStickersUpdate(GuildStickersUpdate),
+    GuildRoleCreate(GuildRole),
+    GuildRoleUpdate(GuildRole),
+    GuildRoleDelete(GuildRoleDelete),
+    GuildMemberAdd(GuildMemberEvent),
+    GuildMemberUpdate(GuildMemberEvent),
+    GuildMemberRemove(GuildMemberRemove),
+    Unknown(String),
+}
+
+impl Event {
+    /// Malformed events are logged and turned into `Event::Unknown`
+    fn parse(name: &str, data: serde_json::Value) -> Self {
+        let event = match name {
+            "READY" => serde_json::from_value(data).map(Event::Ready),
+            "RESUMED" => Ok(Event::Resumed),
+            "GUILD_CREATE" => serde_json::from_value(data).map(|g| Event::GuildCreate(Box::new(g))),
+            "GUILD_UPDATE" => serde_json::from_value(data).map(Event::GuildUpdate),
+            "GUILD_DELETE" => serde_json::from_value(data).map(Event::GuildDelete),
+            "GUILD_EMOJIS_UPDATE" => serde_json::from_value(data).map(Event::GuildEmojisUpdate),
+            "GUILD_STICKERS_UPDATE" => serde_json::from_value(data).map(Event::GuildStickersUpdate),
+            "GUILD_ROLE_CREATE" => serde_json::from_value(data).map(Event::GuildRoleCreate),
+            "GUILD_ROLE_UPDATE" => serde_json::from_value(data).map(Event::GuildRoleUpdate),
+            "GUILD_ROLE_DELETE" => serde_json::from_value(data).map(Event::GuildRoleDelete),
+            "GUILD_MEMBER_ADD" => serde_json::from_value(data).map(Event::GuildMemberAdd),
+            "GUILD_MEMBER_UPDATE" => serde_json::from_value(data).map(Event::GuildMemberUpdate),
+            "GUILD_MEMBER_REMOVE" => serde_json::from_value(data).map(Event::GuildMemberRemove),
+            _ => Ok(Event::Unknown(name.to_string())),
+        };
+        event.unwrap_or_else(|e| {
+            error!("Error while parsing {name} event: {e:?}");
+            Event::Unknown(name.to_string())
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Ready {
+    pub session_id: String,
+    pub resume_gateway_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuildUpdate {
+    #[serde(deserialize_with = "deserialize_str")]
+    pub id: u64,
+    pub name: String,
+    pub icon: Option<String>,
+    pub description: Option<String>,
+    #[serde(deserialize_with = "deserialize_str")]
+    pub owner_id: u64,
+    pub roles: Vec<types::Role>,
+    #[serde(default)]
+    pub premium_tier: u8,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuildUnavailable {
+    #[serde(deserialize_with = "deserialize_str")]
+    pub id: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuildEmojisUpdate {
+    #[serde(deserialize_with = "deserialize_str")]
+    pub guild_id: u64,
+    pub emojis: Vec<types::EmojiItem>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuildStickersUpdate {
+    #[serde(deserialize_with = "deserialize_str")]
+    pub guild_id: u64,
+    pub stickers: Vec<types::StickerItem>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuildRole {
+    #[serde(deserialize_with = "deserialize_str")]
+    pub guild_id: u64,
+    pub role: types::Role,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuildRoleDelete {
+    #[serde(deserialize_with = "deserialize_str")]
+    pub guild_id: u64,
+    #[serde(deserialize_with = "deserialize_str")]
+    pub role_id: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuildMemberEvent {
+    #[serde(deserialize_with = "deserialize_str")]
+    pub guild_id: u64,
+    #[serde(flatten)]
+    pub member: types::GuildMember,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuildMemberRemove {
+    #[serde(deserialize_with = "deserialize_str")]
+    pub guild_id: u64,
+    pub user: types::User,
+}
+
+/// Something that reacts to gateway events
+#[async_trait]
+pub trait EventHandler: Send + Sync {
+    async fn handle(&self, event: &Event);
+}
+
+/// Feed every event of the stream to the handlers, in order
+pub async fn dispatch(
+    mut events: tokio_stream::wrappers::ReceiverStream<Event>,
+    handlers: Vec<Box<dyn EventHandler>>,
+) {
+    while let Some(event) = events.next().await {
+        for handler in &handlers {
+            handler.handle(&event).await;
+        }
+    }
+}
+
+/// Keeps the guilds cached in `Logic::guilds` up to date
+pub struct GuildCache(
+    pub &'static dashmap::DashMap<u64, types::PartialGuild, fxhash::FxBuildHasher>,
+);
+
+#[async_trait]
+impl EventHandler for GuildCache {
+    async fn handle(&self, event: &Event) {
+        let guilds = self.0;
+        match event {
+            Event::GuildCreate(guild) => {
+                debug!("Got GUILD_CREATE");
+                guilds.insert(guild.id, (**guild).clone());
+            }
+            Event::GuildUpdate(update) => {
+                debug!("Got GUILD_UPDATE");
+                if let Some(mut guild) = guilds.get_mut(&update.id) {
+                    guild.name = update.name.clone();
+                    guild.icon = update.icon.clone();
+                    guild.description = update.description.clone();
+                    guild.owner_id = update.owner_id;
+                    guild.roles = update.roles.clone();
+                    guild.premium_tier = update.premium_tier;
+                }
+            }
+            Event::GuildDelete(guild) => {
+                debug!("Got GUILD_DELETE");
+                guilds.remove(&guild.id);
+            }
+            Event::GuildEmojisUpdate(update) => {
+                debug!("Got GUILD_EMOJIS_UPDATE");
+                if let Some(mut guild) = guilds.get_mut(&update.guild_id) {
+                    guild.emojis = update.emojis.clone();
+                }
+            }
+            Event::GuildStickersUpdate(update) => {
+                debug!("Got GUILD_STICKERS_UPDATE");
+                if let Some(mut guild) = guilds.get_mut(&update.guild_id) {
+                    guild.stickers = update.stickers.clone();
+                }
+            }
+            Event::GuildRoleCreate(update) | Event::GuildRoleUpdate(update) => {
+                debug!("Got GUILD_ROLE_CREATE/GUILD_ROLE_UPDATE");
+                if let Some(mut guild) = guilds.get_mut(&update.guild_id) {
+                    guild.upsert_role(update.role.clone());
+                }
+            }
+            Event::GuildRoleDelete(update) => {
+                debug!("Got GUILD_ROLE_DELETE");
+                if let Some(mut guild) = guilds.get_mut(&update.guild_id) {
+                    guild.remove_role(update.role_id);
+                }
+            }
+            Event::GuildMemberAdd(update) | Event::GuildMemberUpdate(update) => {
+                debug!("Got GUILD_MEMBER_ADD/GUILD_MEMBER_UPDATE");
+                if let Some(mut guild) = guilds.get_mut(&update.guild_id) {
+                    guild.upsert_member(update.member.clone());
+                }
+            }
+            Event::GuildMemberRemove(update) => {
+                debug!("Got GUILD_MEMBER_REMOVE");
+                if let Some(mut guild) = guilds.get_mut(&update.guild_id) {
+                    guild.members.retain(|m| m.user.id != update.user.id);
+                }
+            }
+            Event::Ready(_) | Event::Resumed => {}
+            Event::Unknown(event_name) => trace!("Unhandled event: {event_name}"),
+        }
+    }
+}
+
+struct GatewaySession {
+    session_id: String,
+    resume_gateway_url: String,
+}
+
+/// How a gateway connection ended, and what the next one should do
+enum ConnectionEnd {
+    Shutdown,
+    Resume,
+    Reidentify,
+    /// Identify again without the GUILD_MEMBERS intent
+    DropMembersIntent,
+    Fatal,
+}
+
+fn gateway_message(payload: &serde_json::Value) -> tokio_tungstenite::tungstenite::Message {
+    tokio_tungstenite::tungstenite::Message::Text(payload.to_string())
+}
+
+pub struct GatewayClient {
+    url: String,
+    token: String,
+    intents: u64,
+    shutdown: std::sync::Arc<tokio::sync::Notify>,
+}
+
+impl GatewayClient {
+    pub fn new(token: String, intents: u64) -> Self {
+        Self::with_url(DISCORD_WS.to_string(), token, intents)
+    }
+
+    /// Connect to another gateway than Discord's, `url` must carry the query string
+    pub fn with_url(url: String, token: String, intents: u64) -> Self {
+        GatewayClient {
+            url,
+            token,
+            intents,
+            shutdown: std::sync::Arc::new(tokio::sync::Notify::new()),
+        }
+    }
+
+    /// Notifying the handle closes the connection, the client won't reconnect after this
+    pub fn shutdown_handle(&self) -> std::sync::Arc<tokio::sync::Notify> {
+        self.shutdown.clone()
+    }
+
+    /// Connect to the gateway and stream the dispatched events.
+    /// The stream ends on shutdown or when Discord refuses the connection for good.
+    pub fn events(self) -> tokio_stream::wrappers::ReceiverStream<Event> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(256);
+        tokio::spawn(self.run(sender));
+        tokio_stream::wrappers::ReceiverStream::new(receiver)
+    }
+
+    /// Keep a gateway connection alive until shutdown.
+    /// Dropped connections are resumed on `resume_gateway_url` when Discord allows it,
+    /// otherwise a new session is identified.
+    async fn run(self, events: tokio::sync::mpsc::Sender<Event>) {
+        let sequence = std::sync::Arc::new(std::sync::atomic::AtomicI64::new(-1));
+        let mut session: Option<GatewaySession> = None;
+        let mut backoff = std::time::Duration::from_secs(1);
+        let mut intents = self.intents;
+
+        loop {
+            let url = match &session {
+                Some(s) => format!("{}/?v=10&encoding=json", s.resume_gateway_url),
+                None => self.url.clone(),
+            };
+            let ws = tokio::select! {
+                _ = self.shutdown.notified() => return,
+                ws = tokio_tungstenite::connect_async(url) => ws,
+            };
+            let ws = match ws {
+                Ok((ws, _)) => ws,
+                Err(e) => {
+                    error!("Error when connecting to discord gateway: {e}");
+                    tokio::select! {
+                        _ = self.shutdown.notified() => return,
+                        _ = tokio::time::sleep(backoff) => {},
+                    };
+                    backoff = (backoff * 2).min(std::time::Duration::from_secs(60));
+                    continue;
+                }
+            };
+            backoff = std::time::Duration::from_secs(1);
+
+            match self
+                .run_connection(ws, intents, &mut session, &sequence, &events)
+                .await
+            {
+                ConnectionEnd::Shutdown => return,
+                ConnectionEnd::Resume => {
+                    info!("[WS] Reconnecting to the gateway");
+                }
+                ConnectionEnd::Reidentify => {
+                    info!("[WS] Starting a new gateway session");
+                    session = None;
+                    sequence.store(-1, std::sync::atomic::Ordering::Relaxed);
+                }
+                ConnectionEnd::DropMembersIntent => {
+                    error!(
+                        "[WS] Discord refused the GUILD_MEMBERS intent, enable the Server \
+                         Members Intent of the bot in the developer portal. \
+                         Connecting without it, member roles are fetched from the API instead."
+                    );
+                    intents &= !GUILD_MEMBERS;
+                    session = None;
+                    sequence.store(-1, std::sync::atomic::Ordering::Relaxed);
+                }
+                ConnectionEnd::Fatal => {
+                    error!("[WS] Gateway closed the connection for good, giving up");
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn run_connection(
+        &self,
+        ws: WsStream,
+        intents: u64,
+        session: &mut Option<GatewaySession>,
+        sequence: &std::sync::Arc<std::sync::atomic::AtomicI64>,
+        events: &tokio::sync::mpsc::Sender<Event>,
+    ) -> ConnectionEnd {
+        use rand::{Rng, SeedableRng};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (client_writer, mut client_reader) = ws.split();
+        let client_writer = std::sync::Arc::new(tokio::sync::Mutex::new(client_writer));
+        let force_heartbeat = std::sync::Arc::new(tokio::sync::Notify::new());
+        let zombied = std::sync::Arc::new(tokio::sync::Notify::new());
+        let acked = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let mut heartbeat: Option<tokio::task::JoinHandle<()>> = None;
+
+        let end = loop {
+            let message = tokio::select! {
+                _ = self.shutdown.notified() => {
+                    let _ = client_writer.lock().await.close().await;
+                    break ConnectionEnd::Shutdown;
+                }
+                _ = zombied.notified() => {
+                    warn!("[WS] No heartbeat ACK from the gateway");
+                    break ConnectionEnd::Resume;
+                }
+                m = client_reader.next() => m,
+            };
+            let m = match message {
+                None => {
+                    info!("[WS] Gateway connection closed");
+                    break ConnectionEnd::Resume;
+                }
+                Some(Err(e)) => {
+                    error!("Error with the gateway connection: {e}");
+                    break ConnectionEnd::Resume;
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    let code = frame.map(|f| u16::from(f.code)).unwrap_or(1000);
+                    info!("[WS] Gateway closed the connection with code {code}");
+                    break match code {
+                        // Disallowed intents, only GUILD_MEMBERS is privileged
+                        4014 if intents & GUILD_MEMBERS != 0 => ConnectionEnd::DropMembersIntent,
+                        // Authentication failed, invalid shard, sharding required,
+                        // invalid API version, invalid or disallowed intents
+                        4004 | 4010 | 4011 | 4012 | 4013 | 4014 => ConnectionEnd::Fatal,
+                        // Invalid sequence or session timed out
+                        4007 | 4009 => ConnectionEnd::Reidentify,
+                        _ => ConnectionEnd::Resume,
+                    };
+                }
+                Some(Ok(m)) => m,
+            };
+            trace!("[WS] Got message");
+            let payload = match m.to_text() {
+                Ok("") | Err(_) => {
+                    debug!("[WS] Skipped Message");
+                    continue;
+                }
+                Ok(m) => match GatewayPayload::parse(m) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        warn!("[WS] Invalid gateway payload: {e}");
+                        continue;
+                    }
+                },
+            };
+
+            match payload {
+                GatewayPayload::Dispatch { seq, event } => {
+                    if let Some(s) = seq {
+                        sequence.store(s, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    match &event {
+                        Event::Ready(ready) => {
+                            info!("Connected to discord gateway!");
+                            *session = Some(GatewaySession {
+                                session_id: ready.session_id.clone(),
+                                resume_gateway_url: ready.resume_gateway_url.clone(),
+                            });
+                        }
+                        Event::Resumed => info!("Resumed discord gateway session"),
+                        _ => {}
+                    }
+                    if events.send(event).await.is_err() {
+                        debug!("[WS] Nobody is listening to gateway events anymore");
+                        let _ = client_writer.lock().await.close().await;
+                        break ConnectionEnd::Shutdown;
+                    }
+                }
+                GatewayPayload::Hello { heartbeat_interval } => {
+                    trace!("[WS] Hello from gateway");
+                    if let Some(old) = heartbeat.take() {
+                        old.abort();
+                    }
+                    acked.store(true, std::sync::atomic::Ordering::Relaxed);
+                    heartbeat = Some(tokio::spawn({
+                        let client = client_writer.clone();
+                        let sequence = sequence.clone();
+                        let force_heartbeat = force_heartbeat.clone();
+                        let zombied = zombied.clone();
+                        let acked = acked.clone();
+                        let mut rng = rand::rngs::StdRng::from_entropy();
+                        async move {
+                            // The first heartbeat is jittered, the next ones follow the interval
+                            let mut wait = (heartbeat_interval as f64 * rng.gen::<f64>()) as u64;
+                            loop {
+                                tokio::select!(
+                                    _ = tokio::time::sleep(std::time::Duration::from_millis(wait)) => {
+                                        if !acked.swap(false, std::sync::atomic::Ordering::Relaxed) {
+                                            zombied.notify_one();
+                                            return;
+                                        }
+                                    },
+                                    _ = force_heartbeat.notified() => {}
+                                );
+                                wait = heartbeat_interval;
+                                let seq = sequence.load(std::sync::atomic::Ordering::Relaxed);
+                                match client
+                                    .lock()
+                                    .await
+                                    .send(gateway_message(&json!({
+                                        "op": 1,
+                                        "d": (seq >= 0).then_some(seq),
+                                    })))
+                                    .await
+                                {
+                                    Ok(_) => trace!("[WS] Sent OPCODE 1"),
+                                    Err(e) => {
+                                        error!("[WS] Error when sending gateway: {e}: OPCODE 1")
+                                    }
+                                };
+                            }
+                        }
+                    }));
+
+                    let payload = match session {
+                        Some(s) => json!({
+                            "op": 6,
+                            "d": {
+                                "token": self.token,
+                                "session_id": s.session_id,
+                                "seq": sequence.load(std::sync::atomic::Ordering::Relaxed),
+                            }
+                        }),
+                        None => json!({
+                            "op": 2,
+                            "d": {
+                                "token": self.token,
+                                "intents": intents,
+                                "properties": {
+                                    "os": "linux",
+                                    "browser": "dem.maix.me",
+                                    "device": "dem.maix.me"
+                                }
+                            },
+                        }),
+                    };
+                    let op = &payload["op"];
+                    match client_writer
+                        .lock()
+                        .await
+                        .send(gateway_message(&payload))
+                        .await
+                    {
+                        Ok(_) => debug!("[WS] Sent OPCODE {op}"),
+                        Err(e) => {
+                            error!("[WS] Error when sending gateway: {e}: OPCODE {op}");
+                            break ConnectionEnd::Resume;
+                        }
+                    };
+                }
+                GatewayPayload::Heartbeat => {
+                    trace!("[WS] Force heartbeat");
+                    force_heartbeat.notify_one();
+                }
+                GatewayPayload::Reconnect => {
+                    info!("[WS] Gateway asked for a reconnection");
+                    break ConnectionEnd::Resume;
+                }
+                GatewayPayload::InvalidSession { resumable } => {
+                    info!("[WS] Invalid session (resumable: {resumable})");
+                    let wait = rand::thread_rng().gen_range(1000..5000);
+                    tokio::select! {
+                        _ = self.shutdown.notified() => {
+                            let _ = client_writer.lock().await.close().await;
+                            break ConnectionEnd::Shutdown;
+                        }
+                        _ = tokio::time::sleep(std::time::Duration::from_millis(wait)) => {}
+                    };
+                    break if resumable && session.is_some() {
+                        ConnectionEnd::Resume
+                    } else {
+                        ConnectionEnd::Reidentify
+                    };
+                }
+                GatewayPayload::HeartbeatAck => {
+                    trace!("[WS] Heartbeat ACK");
+                    acked.store(true, std::sync::atomic::Ordering::Relaxed);
+                }
+                GatewayPayload::Unknown(n) => {
+                    debug!("[WS] Got OPCODE '{n}'")
+                }
+            }
+        };
+
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.abort();
+        }
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{sink::SinkExt, stream::StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+    use tokio_tungstenite::tungstenite::Message;
+
+    type ServerWs = tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>;
+
+    /// Long enough for the invalid session wait, which can last up to 5 seconds
+    async fn timeout<F: std::future::Future>(f: F) -> F::Output {
+        tokio::time::timeout(std::time::Duration::from_secs(10), f)
+            .await
+            .expect("timed out waiting for the gateway client")
+    }
+
+    async fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    async fn accept(listener: &TcpListener) -> ServerWs {
+        let (stream, _) = timeout(listener.accept()).await.unwrap();
+        tokio_tungstenite::accept_async(stream).await.unwrap()
+    }
+
+    fn client(
+        url: &str,
+    ) -> (
+        tokio_stream::wrappers::ReceiverStream<Event>,
+        std::sync::Arc<tokio::sync::Notify>,
+    ) {
+        let client = GatewayClient::with_url(
+            format!("{url}/?v=10&encoding=json"),
+            "token".to_string(),
+            INTENTS,
+        );
+        let shutdown = client.shutdown_handle();
+        (client.events(), shutdown)
+    }
+
+    async fn send(ws: &mut ServerWs, payload: serde_json::Value) {
+        ws.send(Message::Text(payload.to_string())).await.unwrap();
+    }
+
+    /// Next payload sent by the client, heartbeats are skipped
+    async fn recv(ws: &mut ServerWs) -> serde_json::Value {
+        loop {
+            let message = timeout(ws.next())
+                .await
+                .expect("the client closed the connection")
+                .unwrap();
+            if let Message::Text(text) = message {
+                let payload: serde_json::Value = serde_json::from_str(&text).unwrap();
+                if payload["op"] != 1 {
+                    return payload;
+                }
+            }
+        }
+    }
+
+    /// Say hello and return how the client answered, identify or resume
+    async fn hello(ws: &mut ServerWs, heartbeat_interval: u64) -> serde_json::Value {
+        send(
+            ws,
+            json!({"op": 10, "d": {"heartbeat_interval": heartbeat_interval}}),
+        )
+        .await;
+        recv(ws).await
+    }
+
+    async fn ready(ws: &mut ServerWs, resume_gateway_url: &str) {
+        send(
+            ws,
+            json!({
+                "op": 0,
+                "s": 1,
+                "t": "READY",
+                "d": {"session_id": "session", "resume_gateway_url": resume_gateway_url},
+            }),
+        )
+        .await;
+    }
+
+    async fn close(ws: &mut ServerWs, code: u16) {
+        let _ = ws
+            .close(Some(CloseFrame {
+                code: CloseCode::from(code),
+                reason: "".into(),
+            }))
+            .await;
+    }
+
+    /// Connect a client through a first session, resumes are expected on the second listener
+    async fn identified() -> (
+        ServerWs,
+        TcpListener,
+        TcpListener,
+        tokio_stream::wrappers::ReceiverStream<Event>,
+    ) {
+        let (gateway, gateway_url) = listen().await;
+        let (resume, resume_url) = listen().await;
+        let (mut events, _) = client(&gateway_url);
+        let mut ws = accept(&gateway).await;
+        assert_eq!(hello(&mut ws, 45000).await["op"], 2);
+        ready(&mut ws, &resume_url).await;
+        assert!(matches!(
+            timeout(events.next()).await,
+            Some(Event::Ready(_))
+        ));
+        (ws, gateway, resume, events)
+    }
+
+    async fn expect_resume(resume: &TcpListener) {
+        let mut ws = accept(resume).await;
+        let payload = hello(&mut ws, 45000).await;
+        assert_eq!(payload["op"], 6);
+        assert_eq!(payload["d"]["session_id"], "session");
+        assert_eq!(payload["d"]["seq"], 1);
+    }
+
+    #[test]
+    fn dispatch_without_event_name_is_unknown() {
+        let payload = GatewayPayload::parse(r#"{"op": 0, "s": 3, "d": {}}"#).unwrap();
+        assert!(matches!(
+            payload,
+            GatewayPayload::Dispatch {
+                seq: Some(3),
+                event: Event::Unknown(name),
+            } if name.is_empty()
+        ));
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let payload = GatewayPayload::parse(r#"{"op": 42, "d": null}"#).unwrap();
+        assert!(matches!(payload, GatewayPayload::Unknown(42)));
+    }
+
+    #[test]
+    fn malformed_event_data_is_unknown() {
+        let payload =
+            GatewayPayload::parse(r#"{"op": 0, "s": 1, "t": "GUILD_CREATE", "d": {"id": []}}"#)
+                .unwrap();
+        assert!(matches!(
+            payload,
+            GatewayPayload::Dispatch {
+                event: Event::Unknown(name),
+                ..
+            } if name == "GUILD_CREATE"
+        ));
+    }
+
+    #[test]
+    fn malformed_payloads_are_errors() {
+        assert!(
+            GatewayPayload::parse(r#"{"op": 10, "d": {"heartbeat_interval": "soon"}}"#).is_err()
+        );
+        assert!(GatewayPayload::parse(r#"{"op": 10}"#).is_err());
+        assert!(GatewayPayload::parse(r#"{"d": {}}"#).is_err());
+        assert!(GatewayPayload::parse("not json").is_err());
+    }
+
+    #[test]
+    fn invalid_session_with_bad_data_is_not_resumable() {
+        let payload = GatewayPayload::parse(r#"{"op": 9, "d": "yes"}"#).unwrap();
+        assert!(matches!(
+            payload,
+            GatewayPayload::InvalidSession { resumable: false }
+        ));
+    }
+
+    #[tokio::test]
+    async fn resumes_on_resume_gateway_url() {
+        let (mut ws, _gateway, resume, _events) = identified().await;
+        close(&mut ws, 4000).await;
+        expect_resume(&resume).await;
+    }
+
+    #[tokio::test]
+    async fn guild_update_without_icon() {
+        let (mut ws, _gateway, _resume, mut events) = identified().await;
+        send(
+            &mut ws,
+            json!({
+                "op": 0,
+                "s": 2,
+                "t": "GUILD_UPDATE",
+                "d": {
+                    "id": "1",
+                    "name": "guild",
+                    "icon": null,
+                    "description": null,
+                    "owner_id": "2",
+                    "roles": [],
+                },
+            }),
+        )
+        .await;
+        match timeout(events.next()).await {
+            Some(Event::GuildUpdate(update)) => {
+                assert_eq!(update.id, 1);
+                assert_eq!(update.icon, None);
+            }
+            e => panic!("expected a GUILD_UPDATE, got {e:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnect_resumes() {
+        let (mut ws, _gateway, resume, _events) = identified().await;
+        send(&mut ws, json!({"op": 7, "d": null})).await;
+        expect_resume(&resume).await;
+    }
+
+    #[tokio::test]
+    async fn resumable_invalid_session_resumes() {
+        let (mut ws, _gateway, resume, _events) = identified().await;
+        send(&mut ws, json!({"op": 9, "d": true})).await;
+        expect_resume(&resume).await;
+    }
+
+    #[tokio::test]
+    async fn invalid_session_identifies_again() {
+        let (mut ws, gateway, _resume, _events) = identified().await;
+        send(&mut ws, json!({"op": 9, "d": false})).await;
+        let mut ws = accept(&gateway).await;
+        assert_eq!(hello(&mut ws, 45000).await["op"], 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown_during_invalid_session_wait() {
+        let (gateway, gateway_url) = listen().await;
+        let (mut events, shutdown) = client(&gateway_url);
+        let mut ws = accept(&gateway).await;
+        assert_eq!(hello(&mut ws, 45000).await["op"], 2);
+        send(&mut ws, json!({"op": 9, "d": false})).await;
+        // Let the client start waiting before reconnecting
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        shutdown.notify_one();
+        let end = tokio::time::timeout(std::time::Duration::from_millis(500), events.next());
+        assert!(end
+            .await
+            .expect("shutdown waited for the backoff")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn zombie_connection_resumes() {
+        let (gateway, gateway_url) = listen().await;
+        let (resume, resume_url) = listen().await;
+        let (_events, _) = client(&gateway_url);
+        let mut ws = accept(&gateway).await;
+        // Heartbeats are never acknowledged
+        assert_eq!(hello(&mut ws, 50).await["op"], 2);
+        ready(&mut ws, &resume_url).await;
+        expect_resume(&resume).await;
+    }
+
+    #[tokio::test]
+    async fn fatal_close_codes_stop_the_client() {
+        for code in [4004, 4010, 4011, 4012, 4013] {
+            let (gateway, gateway_url) = listen().await;
+            let (mut events, _) = client(&gateway_url);
+            let mut ws = accept(&gateway).await;
+            assert_eq!(hello(&mut ws, 45000).await["op"], 2);
+            close(&mut ws, code).await;
+            assert!(
+                timeout(events.next()).await.is_none(),
+                "the client kept going after {code}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn disallowed_intents_identify_without_guild_members() {
+        let (gateway, gateway_url) = listen().await;
+        let (mut events, _) = client(&gateway_url);
+        let mut ws = accept(&gateway).await;
+        let identify = hello(&mut ws, 45000).await;
+        assert_eq!(identify["op"], 2);
+        assert_eq!(identify["d"]["intents"], INTENTS);
+        close(&mut ws, 4014).await;
+
+        let mut ws = accept(&gateway).await;
+        let identify = hello(&mut ws, 45000).await;
+        assert_eq!(identify["op"], 2);
+        assert_eq!(identify["d"]["intents"], INTENTS & !GUILD_MEMBERS);
+
+        // Nothing left to drop
+        close(&mut ws, 4014).await;
+        assert!(timeout(events.next()).await.is_none());
+    }
+}
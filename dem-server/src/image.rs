@@ -1,258 +1,1643 @@
 use crate::*;
+use rocket_db_pools::deadpool_redis::redis::Cmd;
 
-#[derive(Debug)]
+/// Set once the sidecar `<uuid>.json` files of older versions have been imported in the index
+const SIDECARS_IMPORTED_KEY: &str = "dem:images:sidecars_imported";
+
+/// Image files live in a `BlobStore`, their metadata in a Redis hash per guild
 pub struct ImageStore {
-    pub base_path: std::path::PathBuf,
+    pub blobs: Box<dyn crate::blob::BlobStore>,
     pub temp_image_dir: std::path::PathBuf,
-    pub cache: tokio::sync::RwLock<
-        lru::LruCache<u64, std::collections::HashMap<uuid::Uuid, ImageData, fxhash::FxBuildHasher>>,
-    >,
+    db: rocket_db_pools::deadpool_redis::Pool,
+    pub quotas: crate::quota::Quotas,
+    /// Net amount of upvotes needed for a proposal to be approved without a moderator
+    pub vote_threshold: Option<i64>,
+}
+
+/// Takes a slot in the window `KEYS[1]` if less than `ARGV[1]` are taken,
+/// the window lasts `ARGV[2]` seconds from its first upload
+const RESERVE_UPLOAD_SCRIPT: &str = r#"
+local uploads = redis.call('INCR', KEYS[1])
+if uploads == 1 then
+    redis.call('EXPIRE', KEYS[1], ARGV[2])
+end
+if uploads > tonumber(ARGV[1]) then
+    redis.call('DECR', KEYS[1])
+    return 0
+end
+return 1
+"#;
+
+/// Gives a slot back, unless the window already ended
+const RELEASE_UPLOAD_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    redis.call('DECR', KEYS[1])
+end
+return 0
+"#;
+
+/// Sets the field `ARGV[1]` of the hash `KEYS[1]` to `ARGV[3]` if it still holds `ARGV[2]`
+const COMPARE_AND_SET_SCRIPT: &str = r#"
+if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+    redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+    return 1
+end
+return 0
+"#;
+
+/// Approvals retried when the proposal changed under them
+const APPROVE_ATTEMPTS: usize = 5;
+
+/// How long the quota held by an upload outlives a server that died while storing it
+const RESERVATION_SECS: u64 = 600;
+
+/// Quota held by an upload between the quota check and its metadata being written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaReservation {
+    pub uuid: uuid::Uuid,
+    pub user_id: u64,
+    pub bytes: u64,
+}
+
+impl QuotaReservation {
+    fn member(&self) -> String {
+        format!("{}:{}:{}", self.uuid.hyphenated(), self.user_id, self.bytes)
+    }
+
+    fn parse(member: &str) -> Option<Self> {
+        let mut parts = member.split(':');
+        let reservation = QuotaReservation {
+            uuid: uuid::Uuid::parse_str(parts.next()?).ok()?,
+            user_id: parts.next()?.parse().ok()?,
+            bytes: parts.next()?.parse().ok()?,
+        };
+        parts.next().is_none().then_some(reservation)
+    }
+}
+
+fn redis_error(e: impl std::error::Error + Send + Sync + 'static) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e)
+}
+
+fn invalid_data(e: serde_json::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
 }
 
 impl ImageStore {
-    pub fn from_figment(f: &rocket::figment::Figment) -> Self {
+    /// Images are kept in an S3 compatible bucket when `dem.storage.backend` is `s3`,
+    /// in the `dem.image_store` directory otherwise
+    pub fn from_figment(
+        f: &rocket::figment::Figment,
+        db: rocket_db_pools::deadpool_redis::Pool,
+    ) -> Self {
+        let blobs: Box<dyn crate::blob::BlobStore> =
+            match f.extract_inner::<String>("dem.storage.backend").as_deref() {
+                Ok("s3") => Box::new(
+                    f.extract_inner::<crate::blob::S3Config>("dem.storage")
+                        .and_then(crate::blob::S3BlobStore::new)
+                        .expect("Invalid dem.storage configuration"),
+                ),
+                Ok("fs") | Err(_) => Box::new(crate::blob::FsBlobStore::new(
+                    f.extract_inner("dem.image_store")
+                        .expect("You need to specify the image_store property"),
+                )),
+                Ok(backend) => panic!("Unknown storage backend {backend}"),
+            };
         ImageStore {
-            base_path: f
-                .extract_inner("dem.image_store")
-                .expect("You need to specify the image_store property"),
+            blobs,
             temp_image_dir: f
                 .extract_inner("dem.temp_image_dir")
                 .expect("You need to specify the temp_image_dir property"),
-            cache: tokio::sync::RwLock::new(lru::LruCache::new(1024)),
+            db,
+            quotas: crate::quota::Quotas::from_figment(f).expect("Invalid dem.quota configuration"),
+            vote_threshold: f.extract_inner("dem.vote_threshold").ok(),
+        }
+    }
+
+    async fn connection(&self) -> std::io::Result<rocket_db_pools::deadpool_redis::Connection> {
+        self.db.get().await.map_err(redis_error)
+    }
+
+    fn index_key(guildid: u64) -> String {
+        format!("dem:images:{guildid}")
+    }
+
+    /// Every image stored for a guild.
+    /// Entries that can't be parsed are logged and left out instead of failing the listing.
+    pub async fn list(
+        &self,
+        guildid: u64,
+    ) -> std::io::Result<std::collections::HashMap<uuid::Uuid, ImageData, fxhash::FxBuildHasher>>
+    {
+        let entries = Cmd::hgetall(Self::index_key(guildid))
+            .query_async::<_, std::collections::HashMap<String, String>>(
+                &mut self.connection().await?,
+            )
+            .await
+            .map_err(redis_error)?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|(uuid, data)| {
+                match (
+                    uuid::Uuid::parse_str(&uuid),
+                    serde_json::from_str::<ImageData>(&data),
+                ) {
+                    (Ok(uuid), Ok(data)) => Some((uuid, data)),
+                    (Err(e), _) => {
+                        error!("Invalid image id {uuid} in the index of {guildid}: {e}");
+                        None
+                    }
+                    (_, Err(e)) => {
+                        error!("Invalid metadata for image {uuid} of {guildid}: {e}");
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    /// The images of a guild matching `filter`, in the order it asks for
+    pub async fn query(
+        &self,
+        guildid: u64,
+        filter: &ImageFilter<'_>,
+    ) -> std::io::Result<Vec<(uuid::Uuid, ImageData)>> {
+        let name = filter.name.map(str::to_lowercase);
+        let mut images = self
+            .list(guildid)
+            .await?
+            .into_iter()
+            .filter(|(_, data)| filter.status.map_or(true, |s| data.status == s))
+            .filter(|(_, data)| filter.kind.map_or(true, |k| data.kind == k))
+            .filter(|(_, data)| {
+                filter
+                    .uploader_id
+                    .map_or(true, |id| data.uploader_id == Some(id))
+            })
+            .filter(|(_, data)| {
+                name.as_ref()
+                    .map_or(true, |n| data.name.to_lowercase().contains(n))
+            })
+            .collect::<Vec<_>>();
+        images.sort_by(|(a_uuid, a), (b_uuid, b)| {
+            let ordering = match filter.sort {
+                SortBy::Name => a.name.cmp(&b.name),
+                SortBy::Created => a.created_at.cmp(&b.created_at),
+                SortBy::Updated => a.updated_at.cmp(&b.updated_at),
+                SortBy::Size => a.size.cmp(&b.size),
+            }
+            .then_with(|| a_uuid.cmp(b_uuid));
+            if filter.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        Ok(images)
+    }
+
+    fn uploads_key(guildid: u64, user_id: u64) -> String {
+        format!("dem:uploads:{guildid}:{user_id}")
+    }
+
+    /// Take a slot in the current upload window of `user_id`, unless its `max` uploads are used
+    pub async fn reserve_upload(
+        &self,
+        guildid: u64,
+        user_id: u64,
+        max: u64,
+    ) -> std::io::Result<bool> {
+        rocket_db_pools::deadpool_redis::redis::cmd("EVAL")
+            .arg(RESERVE_UPLOAD_SCRIPT)
+            .arg(1)
+            .arg(Self::uploads_key(guildid, user_id))
+            .arg(max)
+            .arg(self.quotas.window_secs)
+            .query_async::<_, bool>(&mut self.connection().await?)
+            .await
+            .map_err(redis_error)
+    }
+
+    /// Give back a slot taken by an upload that wasn't accepted
+    pub async fn release_upload(&self, guildid: u64, user_id: u64) -> std::io::Result<()> {
+        rocket_db_pools::deadpool_redis::redis::cmd("EVAL")
+            .arg(RELEASE_UPLOAD_SCRIPT)
+            .arg(1)
+            .arg(Self::uploads_key(guildid, user_id))
+            .query_async::<_, ()>(&mut self.connection().await?)
+            .await
+            .map_err(redis_error)
+    }
+
+    pub async fn uploads_in_window(&self, guildid: u64, user_id: u64) -> std::io::Result<u64> {
+        Cmd::get(Self::uploads_key(guildid, user_id))
+            .query_async::<_, Option<u64>>(&mut self.connection().await?)
+            .await
+            .map(Option::unwrap_or_default)
+            .map_err(redis_error)
+    }
+
+    fn reservations_key(guildid: u64) -> String {
+        format!("dem:quota:reserved:{guildid}")
+    }
+
+    /// Count `reservation` in the quotas of the guild until it's released.
+    /// Reservations of uploads that never finished expire after `RESERVATION_SECS`.
+    pub async fn reserve_quota(
+        &self,
+        guildid: u64,
+        reservation: &QuotaReservation,
+    ) -> std::io::Result<()> {
+        let key = Self::reservations_key(guildid);
+        let mut con = self.connection().await?;
+        Cmd::zadd(&key, reservation.member(), unix_now() + RESERVATION_SECS)
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(redis_error)?;
+        Cmd::expire(&key, RESERVATION_SECS as usize)
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(redis_error)
+    }
+
+    pub async fn release_quota(
+        &self,
+        guildid: u64,
+        reservation: &QuotaReservation,
+    ) -> std::io::Result<()> {
+        Cmd::zrem(Self::reservations_key(guildid), reservation.member())
+            .query_async::<_, ()>(&mut self.connection().await?)
+            .await
+            .map_err(redis_error)
+    }
+
+    /// Uploads of the guild holding a part of its quotas
+    pub async fn quota_reservations(&self, guildid: u64) -> std::io::Result<Vec<QuotaReservation>> {
+        let key = Self::reservations_key(guildid);
+        let mut con = self.connection().await?;
+        Cmd::zrembyscore(&key, "-inf", unix_now())
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(redis_error)?;
+        let members = Cmd::zrange(&key, 0, -1)
+            .query_async::<_, Vec<String>>(&mut con)
+            .await
+            .map_err(redis_error)?;
+        Ok(members
+            .iter()
+            .filter_map(|m| QuotaReservation::parse(m))
+            .collect())
+    }
+
+    pub fn image_key(guildid: u64, uuid: &uuid::Uuid) -> String {
+        format!("{guildid}/{}", uuid.hyphenated())
+    }
+
+    /// The upload as it was sent, kept when the served image had to be optimized
+    pub fn original_key(guildid: u64, uuid: &uuid::Uuid) -> String {
+        format!("{guildid}/{}.original", uuid.hyphenated())
+    }
+
+    /// Where older versions kept the metadata, next to the image
+    fn sidecar_key(guildid: u64, uuid: &uuid::Uuid) -> String {
+        format!("{guildid}/{}.json", uuid.hyphenated())
+    }
+
+    pub async fn read_image(&self, guildid: u64, uuid: &uuid::Uuid) -> std::io::Result<Vec<u8>> {
+        self.blobs.get(&Self::image_key(guildid, uuid)).await
+    }
+
+    pub async fn read_metadata(
+        &self,
+        guildid: u64,
+        uuid: &uuid::Uuid,
+    ) -> std::io::Result<ImageData> {
+        let data = Cmd::hget(Self::index_key(guildid), uuid.hyphenated().to_string())
+            .query_async::<_, Option<String>>(&mut self.connection().await?)
+            .await
+            .map_err(redis_error)?
+            .ok_or(std::io::ErrorKind::NotFound)?;
+        serde_json::from_str(&data).map_err(invalid_data)
+    }
+
+    /// Write the metadata of the image in the index, bumping its `updated_at`
+    pub async fn write_metadata(
+        &self,
+        guildid: u64,
+        uuid: uuid::Uuid,
+        mut metadata: ImageData,
+    ) -> std::io::Result<()> {
+        metadata.updated_at = unix_now();
+        let data = serde_json::to_string(&metadata).map_err(invalid_data)?;
+        Cmd::hset(
+            Self::index_key(guildid),
+            uuid.hyphenated().to_string(),
+            data,
+        )
+        .query_async::<_, ()>(&mut self.connection().await?)
+        .await
+        .map_err(redis_error)
+    }
+
+    /// Approve a pending proposal, unless something else changed its status first.
+    /// Returns the metadata as it ends up.
+    pub async fn approve_pending(
+        &self,
+        guildid: u64,
+        uuid: &uuid::Uuid,
+    ) -> std::io::Result<ImageData> {
+        let key = Self::index_key(guildid);
+        let field = uuid.hyphenated().to_string();
+        let mut con = self.connection().await?;
+        // Only replaces the entry if it's still the one the status was read from
+        for _ in 0..APPROVE_ATTEMPTS {
+            let current = Cmd::hget(&key, &field)
+                .query_async::<_, Option<String>>(&mut con)
+                .await
+                .map_err(redis_error)?
+                .ok_or(std::io::ErrorKind::NotFound)?;
+            let mut metadata = serde_json::from_str::<ImageData>(&current).map_err(invalid_data)?;
+            if metadata.status != ProposalStatus::Pending {
+                return Ok(metadata);
+            }
+            metadata.status = ProposalStatus::Approved;
+            metadata.updated_at = unix_now();
+            let approved = serde_json::to_string(&metadata).map_err(invalid_data)?;
+            let swapped = rocket_db_pools::deadpool_redis::redis::cmd("EVAL")
+                .arg(COMPARE_AND_SET_SCRIPT)
+                .arg(1)
+                .arg(&key)
+                .arg(&field)
+                .arg(&current)
+                .arg(&approved)
+                .query_async::<_, bool>(&mut con)
+                .await
+                .map_err(redis_error)?;
+            if swapped {
+                return Ok(metadata);
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "the proposal kept changing while approving it",
+        ))
+    }
+
+    /// Remove the image, its original, its metadata and its votes.
+    /// The metadata goes first so a failure never leaves a listed image without its file.
+    pub async fn delete(&self, guildid: u64, uuid: &uuid::Uuid) -> std::io::Result<()> {
+        let mut con = self.connection().await?;
+        Cmd::hdel(Self::index_key(guildid), uuid.hyphenated().to_string())
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(redis_error)?;
+        Cmd::del(votes_key(guildid, uuid))
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(redis_error)?;
+        self.blobs.delete(&Self::sidecar_key(guildid, uuid)).await?;
+        self.blobs.delete(&Self::image_key(guildid, uuid)).await?;
+        self.blobs.delete(&Self::original_key(guildid, uuid)).await
+    }
+
+    /// Import the `<guildid>/<uuid>.json` files written by older versions in the index.
+    /// Runs once, the sidecar files are left in place and ignored afterwards.
+    pub async fn import_sidecars(&self) -> std::io::Result<usize> {
+        let mut con = self.connection().await?;
+        if Cmd::exists(SIDECARS_IMPORTED_KEY)
+            .query_async::<_, bool>(&mut con)
+            .await
+            .map_err(redis_error)?
+        {
+            return Ok(0);
+        }
+
+        let mut imported = 0;
+        for key in self.blobs.list("").await? {
+            let (guildid, uuid) = match key
+                .strip_suffix(".json")
+                .and_then(|k| k.split_once('/'))
+                .and_then(|(guildid, uuid)| {
+                    Some((
+                        guildid.parse::<u64>().ok()?,
+                        uuid::Uuid::parse_str(uuid).ok()?,
+                    ))
+                }) {
+                Some(k) => k,
+                None => continue,
+            };
+            let mut data =
+                match self.blobs.get(&key).await.and_then(|bytes| {
+                    serde_json::from_slice::<ImageData>(&bytes).map_err(invalid_data)
+                }) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        error!("Skipping sidecar {key}: {e}");
+                        continue;
+                    }
+                };
+            match self.read_image(guildid, &uuid).await {
+                Ok(image) => {
+                    data.size = image.len() as u64;
+                    data.dimensions = data.image_type.dimensions(&image);
+                    data.frames = data.image_type.frame_count(&image);
+                }
+                Err(e) => warn!("Image of sidecar {key} can't be read: {e}"),
+            }
+            if let Ok(original) = self.blobs.get(&Self::original_key(guildid, &uuid)).await {
+                data.original_size = original.len() as u64;
+            }
+            let data = serde_json::to_string(&data).map_err(invalid_data)?;
+            // Entries already in the index are newer than their sidecar
+            Cmd::hset_nx(
+                Self::index_key(guildid),
+                uuid.hyphenated().to_string(),
+                data,
+            )
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(redis_error)?;
+            imported += 1;
         }
+
+        Cmd::set(SIDECARS_IMPORTED_KEY, 1)
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(redis_error)?;
+        Ok(imported)
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, FromFormField, JsonSchema)]
+pub enum SortBy {
+    #[default]
+    Name,
+    Created,
+    Updated,
+    Size,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImageFilter<'a> {
+    pub status: Option<ProposalStatus>,
+    pub kind: Option<ImageKind>,
+    pub uploader_id: Option<u64>,
+    /// Case insensitive part of the name
+    pub name: Option<&'a str>,
+    pub sort: SortBy,
+    pub descending: bool,
+}
+
+/// Seconds since the unix epoch
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct ImageData {
     name: String,
     #[serde(rename = "type")]
     image_type: ImageType,
+    #[serde(default)]
+    kind: ImageKind,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    tags: Option<String>,
+    #[serde(default)]
+    emoji_id: Option<u64>,
+    #[serde(default)]
+    sticker_id: Option<u64>,
+    #[serde(default)]
+    status: ProposalStatus,
+    /// What the moderation provider answered for the upload
+    #[serde(default)]
+    rating: Option<crate::moderation::ImageRating>,
+    #[serde(default)]
+    moderation_log: Vec<ModerationDecision>,
+    /// Perceptual hashes of the image, see `crate::dedup`
+    #[serde(default)]
+    hashes: Vec<u64>,
+    #[serde(default)]
+    uploader_id: Option<u64>,
+    /// Discord username of the uploader when the image was uploaded
+    #[serde(default)]
+    uploader_name: Option<String>,
+    /// Name of the uploaded file, when the client sent one
+    #[serde(default)]
+    original_filename: Option<String>,
+    /// Seconds since the unix epoch, `0` for images uploaded before it was recorded
+    #[serde(default)]
+    created_at: u64,
+    #[serde(default)]
+    updated_at: u64,
+    /// Size in bytes of the served image
+    #[serde(default)]
+    size: u64,
+    /// Size in bytes of the original kept next to the served image, `0` without one
+    #[serde(default)]
+    original_size: u64,
+    #[serde(default)]
+    dimensions: Option<(u32, u32)>,
+    /// `1` for still images
+    #[serde(default)]
+    frames: Option<u32>,
+}
+
+impl ImageData {
+    /// Bytes taken in the store, the original included
+    pub fn stored_size(&self) -> u64 {
+        self.size + self.original_size
+    }
+
+    pub fn uploader_id(&self) -> Option<u64> {
+        self.uploader_id
+    }
+
+    fn new(name: String, image_type: ImageType, kind: ImageKind) -> Self {
+        ImageData {
+            name,
+            image_type,
+            kind,
+            description: None,
+            tags: None,
+            emoji_id: None,
+            sticker_id: None,
+            status: ProposalStatus::Pending,
+            rating: None,
+            moderation_log: Vec::new(),
+            hashes: Vec::new(),
+            uploader_id: None,
+            uploader_name: None,
+            original_filename: None,
+            created_at: 0,
+            updated_at: 0,
+            size: 0,
+            original_size: 0,
+            dimensions: None,
+            frames: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, JsonSchema)]
+pub struct ModerationDecision {
+    moderator_id: u64,
+    approved: bool,
+    /// Seconds since the unix epoch
+    timestamp: u64,
+}
+
+impl ModerationDecision {
+    fn new(moderator_id: u64, approved: bool) -> Self {
+        ModerationDecision {
+            moderator_id,
+            approved,
+            timestamp: unix_now(),
+        }
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    JsonSchema,
+    FromFormField,
+)]
+pub enum ProposalStatus {
+    #[default]
+    Pending,
+    /// Borderline rating, waiting for a moderator before being proposed
+    Quarantined,
+    Approved,
+    Rejected,
+    Published,
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    JsonSchema,
+    FromFormField,
+)]
+pub enum ImageKind {
+    #[default]
+    Emoji,
+    Sticker,
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize, JsonSchema)]
 pub enum ImageType {
     Gif,
     Png,
+    Apng,
+    Lottie,
 }
 
 impl ImageType {
-    fn to_content_type(&self) -> rocket::http::ContentType {
+    pub(crate) fn to_content_type(&self) -> rocket::http::ContentType {
         match self {
             Self::Gif => rocket::http::ContentType::GIF,
-            Self::Png => rocket::http::ContentType::PNG,
+            Self::Png | Self::Apng => rocket::http::ContentType::PNG,
+            Self::Lottie => rocket::http::ContentType::JSON,
+        }
+    }
+
+    pub(crate) fn file_name(&self, name: &str) -> String {
+        match self {
+            Self::Gif => format!("{name}.gif"),
+            Self::Png | Self::Apng => format!("{name}.png"),
+            Self::Lottie => format!("{name}.json"),
         }
     }
+
+    fn dimensions(&self, bytes: &[u8]) -> Option<(u32, u32)> {
+        match self {
+            Self::Png | Self::Apng => png_dimensions(bytes),
+            Self::Lottie => lottie_dimensions(bytes),
+            // `image` is this module, the crate needs its full path
+            Self::Gif => ::image::io::Reader::with_format(
+                std::io::Cursor::new(bytes),
+                ::image::ImageFormat::Gif,
+            )
+            .into_dimensions()
+            .ok(),
+        }
+    }
+
+    fn frame_count(&self, bytes: &[u8]) -> Option<u32> {
+        match self {
+            Self::Png => Some(1),
+            Self::Apng => apng_frame_count(bytes),
+            Self::Lottie => lottie_frame_count(bytes),
+            // Decoded within the limits of `processing`, GIFs that are `TooLarge` have no count
+            Self::Gif => match crate::processing::decode_gif(bytes) {
+                Ok(frames) => u32::try_from(frames.len()).ok(),
+                Err(crate::processing::ProcessingError::TooLarge) => {
+                    debug!("GIF too large to count its frames");
+                    None
+                }
+                Err(_) => None,
+            },
+        }
+    }
+}
+
+const STICKER_MAX_SIZE: u64 = 512 * 1024;
+const STICKER_DIMENSIONS: (u32, u32) = (320, 320);
+
+/// Check that `bytes` is a sticker Discord accepts, for stickers not sent with a content type
+pub(crate) fn sticker_type(bytes: &[u8]) -> Result<ImageType, &'static str> {
+    if bytes.len() as u64 > STICKER_MAX_SIZE {
+        return Err("Stickers can't be bigger than 512 KiB");
+    }
+    let (image_type, dimensions) = if bytes.starts_with(b"\x89PNG") {
+        let image_type = if png_is_animated(bytes) {
+            ImageType::Apng
+        } else {
+            ImageType::Png
+        };
+        (image_type, png_dimensions(bytes))
+    } else {
+        (ImageType::Lottie, lottie_dimensions(bytes))
+    };
+    match dimensions {
+        Some(d) if d == STICKER_DIMENSIONS => Ok(image_type),
+        Some(_) => Err("Stickers must be 320x320"),
+        None => Err("Stickers must be a PNG, an APNG or a Lottie animation"),
+    }
+}
+
+pub fn is_valid_emoji_name(name: &str) -> bool {
+    (2..=32).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Read the width and height out of the IHDR chunk of a PNG file
+fn png_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if bytes.len() < 24 || !bytes.starts_with(SIGNATURE) || &bytes[12..16] != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(bytes[16..20].try_into().ok()?);
+    let height = u32::from_be_bytes(bytes[20..24].try_into().ok()?);
+    Some((width, height))
+}
+
+/// An APNG is a PNG with an `acTL` chunk placed before the first `IDAT`
+fn png_is_animated(bytes: &[u8]) -> bool {
+    apng_frame_count(bytes).is_some()
+}
+
+/// The frame count from the `acTL` chunk, `None` when the PNG isn't animated
+fn apng_frame_count(bytes: &[u8]) -> Option<u32> {
+    let mut offset = 8;
+    while offset + 8 <= bytes.len() {
+        let length = u32::from_be_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ]) as usize;
+        match &bytes[offset + 4..offset + 8] {
+            b"acTL" => {
+                return Some(u32::from_be_bytes(
+                    bytes.get(offset + 8..offset + 12)?.try_into().ok()?,
+                ))
+            }
+            b"IDAT" => return None,
+            _ => offset += length + 12,
+        }
+    }
+    None
+}
+
+fn lottie_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    #[derive(serde::Deserialize)]
+    struct Lottie {
+        w: u32,
+        h: u32,
+    }
+    serde_json::from_slice::<Lottie>(bytes)
+        .ok()
+        .map(|l| (l.w, l.h))
+}
+
+/// Lottie animations play from the `ip` frame to the `op` frame
+fn lottie_frame_count(bytes: &[u8]) -> Option<u32> {
+    #[derive(serde::Deserialize)]
+    struct Lottie {
+        ip: f64,
+        op: f64,
+    }
+    serde_json::from_slice::<Lottie>(bytes)
+        .ok()
+        .map(|l| (l.op - l.ip).max(0.0).round() as u32)
+}
+
+/// The name of the uploaded file without its directories, as the client sent it.
+/// Only meant to be displayed.
+fn original_filename(file: &rocket::fs::TempFile<'_>) -> Option<String> {
+    let raw = file.raw_name()?.dangerous_unsafe_unsanitized_raw().as_str();
+    let name = raw.rsplit(|c: char| c == '/' || c == '\\').next()?.trim();
+    (!name.is_empty()).then(|| name.chars().take(128).collect())
 }
 
 #[openapi]
 #[post("/upload/<guildid>/store/emoji?<name>", data = "<file>")]
 pub async fn upload_emoji_to_store(
     mut file: rocket::fs::TempFile<'_>,
-    content_type: &rocket::http::ContentType,
     logic: &rocket::State<crate::discord::Logic>,
     store: &rocket::State<ImageStore>,
     guildid: u64,
     user: crate::auth::User,
     name: &str,
-) -> Rsp<String> {
-    use tokio::io::AsyncWriteExt;
-    if name.len() > 32 || name.len() < 2 || name.chars().any(|c| !c.is_ascii_alphanumeric()) {
+) -> Rsp<dem_types::api::UploadResult> {
+    if !is_valid_emoji_name(name) {
         return Rsp::err(
             dem_types::error::Error::InvalidRequest,
             Some("Invalid name".to_string()),
         );
     }
+    // Checked before decoding, only members of the guild get to use the decoder
+    if !is_guild_member(logic, &user, guildid).await {
+        return Rsp::err(
+            dem_types::error::Error::Unauthorized,
+            Some("You are not in the guild".to_string()),
+        );
+    }
     let uuid = uuid::Uuid::new_v4();
-    let file_path = format!("/tmp/dem/{uuid}");
-    file.persist_to(&file_path).await.unwrap();
+    let file_path = store.temp_image_dir.join(uuid.hyphenated().to_string());
+    if let Err(e) = file.persist_to(&file_path).await {
+        error!("Error when persisting uploaded emoji: {e}");
+        return Rsp::err(dem_types::error::Error::Internal, None);
+    }
+    let res = async {
+        let bytes = match tokio::fs::read(&file_path).await {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Error when reading uploaded emoji: {e}");
+                return Rsp::err(dem_types::error::Error::Internal, None);
+            }
+        };
 
-    let image_type = if content_type.is_png() {
-        ImageType::Png
-    } else if content_type.is_gif() {
-        ImageType::Gif
-    } else {
+        let processed =
+            match tokio::task::spawn_blocking(move || crate::processing::normalize_emoji(&bytes))
+                .await
+            {
+                Ok(Ok(p)) => p,
+                Ok(Err(e)) => {
+                    return Rsp::err(dem_types::error::Error::InvalidRequest, Some(e.to_string()))
+                }
+                Err(e) => {
+                    error!("Error when processing uploaded emoji: {e}");
+                    return Rsp::err(dem_types::error::Error::Internal, None);
+                }
+            };
+
+        let mut metadata = ImageData::new(name.to_string(), processed.image_type, ImageKind::Emoji);
+        metadata.original_filename = original_filename(&file);
+        store_upload(
+            &file_path,
+            Some(processed.data),
+            logic,
+            store,
+            guildid,
+            &user,
+            uuid,
+            metadata,
+        )
+        .await
+    }
+    .await;
+    remove_temp_upload(&file_path).await;
+    res
+}
+
+#[openapi]
+#[post(
+    "/upload/<guildid>/store/sticker?<name>&<description>&<tags>",
+    data = "<file>"
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_sticker_to_store(
+    mut file: rocket::fs::TempFile<'_>,
+    content_type: &rocket::http::ContentType,
+    logic: &rocket::State<crate::discord::Logic>,
+    store: &rocket::State<ImageStore>,
+    guildid: u64,
+    user: crate::auth::User,
+    name: &str,
+    description: Option<&str>,
+    tags: &str,
+) -> Rsp<dem_types::api::UploadResult> {
+    if !(2..=30).contains(&name.len()) {
         return Rsp::err(
             dem_types::error::Error::InvalidRequest,
-            Some("Invalid body".to_string()),
+            Some("Invalid name".to_string()),
         );
-    };
-
-    let rating = match logic.get_image_rating(&file_path).await.map_err(|e| {
-        error!("Error with Google SafeSearch :{e}");
-        crate::dem_types::error::Error::Internal
-    }) {
-        Ok(v) => v,
-        Err(e) => return Rsp::err(e, Some("Error with Google SafeSearch API".to_string())),
-    };
-    dbg!(rating);
-    if rating < ImageRating::MIN {
+    }
+    if description.map_or(false, |d| !(2..=100).contains(&d.len())) {
         return Rsp::err(
             dem_types::error::Error::InvalidRequest,
-            Some("Image rating not valid".to_string()),
+            Some("Invalid description".to_string()),
         );
     }
-
-    let mut lock = logic.user_cache.write().await;
-
-    let user_permission = lock
-        .get(user.token.as_str())
-        .and_then(|u| {
-            u.guilds
-                .get(&guildid)
-                .map(|&p| p & ((1 << 30) | (1 << 3)) < 1)
-        })
-        .unwrap_or(false);
-    if !user_permission {
+    if tags.is_empty() || tags.len() > 200 {
         return Rsp::err(
-            dem_types::error::Error::Unauthorized,
-            Some("You are not in the guild or don't have permission to do so".to_string()),
+            dem_types::error::Error::InvalidRequest,
+            Some("Invalid tags".to_string()),
         );
     }
-
-    let file_name = format!("{}", uuid.hyphenated());
-    let metadata_filename = format!("{file_name}.json",);
-    let mut file_path = store.base_path.clone();
-    file_path.push(guildid.to_string());
-    file_path.push(&file_name);
-
-    if let Err(e) = file.persist_to(&file_path).await {
-        error!("Error when persisting image to disk: {e}");
+    if file.len() > STICKER_MAX_SIZE {
         return Rsp::err(
-            dem_types::error::Error::Internal,
-            Some("Error when trying to store file".to_string()),
+            dem_types::error::Error::InvalidRequest,
+            Some("Stickers can't be bigger than 512 KiB".to_string()),
         );
     }
+    // Checked before decoding, only members of the guild get to use the decoder
+    if !is_guild_member(logic, &user, guildid).await {
+        return Rsp::err(
+            dem_types::error::Error::Unauthorized,
+            Some("You are not in the guild".to_string()),
+        );
+    }
+    let uuid = uuid::Uuid::new_v4();
+    let file_path = store.temp_image_dir.join(uuid.hyphenated().to_string());
+    if let Err(e) = file.persist_to(&file_path).await {
+        error!("Error when persisting uploaded sticker: {e}");
+        return Rsp::err(dem_types::error::Error::Internal, None);
+    }
+    let res = async {
+        let bytes = match tokio::fs::read(&file_path).await {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Error when reading uploaded sticker: {e}");
+                return Rsp::err(dem_types::error::Error::Internal, None);
+            }
+        };
 
-    file_path.pop();
-    file_path.push(metadata_filename);
-
-    let metadata = ImageData {
-        image_type,
-        name: file_name,
-    };
+        let (image_type, dimensions) = if content_type.is_png()
+            || (content_type.top() == "image" && content_type.sub() == "apng")
+        {
+            let image_type = if png_is_animated(&bytes) {
+                ImageType::Apng
+            } else {
+                ImageType::Png
+            };
+            (image_type, png_dimensions(&bytes))
+        } else if content_type.is_json() {
+            (ImageType::Lottie, lottie_dimensions(&bytes))
+        } else {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Invalid body".to_string()),
+            );
+        };
+        match dimensions {
+            Some(d) if d == STICKER_DIMENSIONS => {}
+            Some(_) => {
+                return Rsp::err(
+                    dem_types::error::Error::InvalidRequest,
+                    Some("Stickers must be 320x320".to_string()),
+                )
+            }
+            None => {
+                return Rsp::err(
+                    dem_types::error::Error::InvalidRequest,
+                    Some("Invalid body".to_string()),
+                )
+            }
+        }
 
-    let mut metadata_file = tokio::fs::File::create(file_path).await.unwrap();
+        let mut metadata = ImageData::new(name.to_string(), image_type, ImageKind::Sticker);
+        metadata.description = description.map(ToString::to_string);
+        metadata.tags = Some(tags.to_string());
+        metadata.original_filename = original_filename(&file);
+        store_upload(
+            &file_path, None, logic, store, guildid, &user, uuid, metadata,
+        )
+        .await
+    }
+    .await;
+    remove_temp_upload(&file_path).await;
+    res
+}
 
-    let metadata_bytes = serde_json::to_vec(&metadata).unwrap();
+/// Any member can propose images, the decoder limits in `processing` bound what they cost
+async fn is_guild_member(
+    logic: &crate::discord::Logic,
+    user: &crate::auth::User,
+    guildid: u64,
+) -> bool {
+    logic
+        .user_cache
+        .write()
+        .await
+        .get(&user.session)
+        .map_or(false, |u| u.guilds.contains_key(&guildid))
+}
 
-    let amount = metadata_file.write(&metadata_bytes).await.unwrap();
-    if amount != metadata_bytes.len() {
-        error!("Invalid metadata write");
+/// The routes persist uploads in `temp_image_dir` while checking them,
+/// they are removed whatever the outcome
+async fn remove_temp_upload(path: &std::path::Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        warn!("Error when removing temporary upload: {e}");
     }
-    let mut cache = store.cache.write().await;
+}
 
-    if let Some(m) = cache.get_mut(&guildid) {
-        m.insert(uuid, metadata);
-    } else {
-        cache.push(guildid, {
-            let mut hm: std::collections::HashMap<uuid::Uuid, ImageData, fxhash::FxBuildHasher> =
-                Default::default();
-            hm.insert(uuid, metadata);
-            hm
-        });
+/// Rate and copy an upload persisted at `tmp_path` into the store, the routes check
+/// membership before reading it.
+/// When the upload was `processed`, the original is kept next to the optimized image.
+#[allow(clippy::too_many_arguments)]
+async fn store_upload(
+    tmp_path: &std::path::Path,
+    processed: Option<Vec<u8>>,
+    logic: &crate::discord::Logic,
+    store: &ImageStore,
+    guildid: u64,
+    user: &crate::auth::User,
+    uuid: uuid::Uuid,
+    mut metadata: ImageData,
+) -> Rsp<dem_types::api::UploadResult> {
+    let (uploader_id, uploader_name) = match logic.user_cache.write().await.get(&user.session) {
+        Some(u) => (u.user_id, u.username.clone()),
+        None => return Rsp::err(dem_types::error::Error::Unauthorized, None),
+    };
+    let window = store.quotas.uploads_per_window;
+    if let Some(max) = window {
+        match store.reserve_upload(guildid, uploader_id, max).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Rsp::err(
+                    dem_types::error::Error::QuotaExceeded,
+                    Some("Too many uploads, try again later".to_string()),
+                )
+            }
+            Err(e) => {
+                error!("Error when counting uploads: {e}");
+                return Rsp::err(dem_types::error::Error::Internal, None);
+            }
+        }
     }
 
-    Rsp::ok(uuid.hyphenated().to_string())
-}
+    let res = async {
+        // Lottie animations are vector JSON documents, image moderation can't rate them
+        if !matches!(metadata.image_type, ImageType::Lottie) {
+            let rating = match logic.moderation.rate(tmp_path).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Error with the moderation provider: {e}");
+                    return Rsp::err(
+                        dem_types::error::Error::Internal,
+                        Some("Error when rating the image".to_string()),
+                    );
+                }
+            };
+            debug!("Upload {uuid} rated {rating:?}");
+            match logic.moderation.verdict(guildid, &rating) {
+                crate::moderation::Verdict::Accept => {}
+                crate::moderation::Verdict::Quarantine => {
+                    metadata.status = ProposalStatus::Quarantined;
+                }
+                crate::moderation::Verdict::Reject => {
+                    return Rsp::err(
+                        dem_types::error::Error::InvalidRequest,
+                        Some("Image rating not valid".to_string()),
+                    );
+                }
+            }
+            metadata.rating = Some(rating);
+        }
 
-#[get("/<guildid>/<uuid>")]
-pub async fn image_serve(
-    uuid: uuid::Uuid,
-    guildid: u64,
-    store: &rocket::State<ImageStore>,
-) -> Result<(rocket::http::ContentType, tokio::fs::File), rocket::http::Status> {
-    let file = {
-        let mut p = store.base_path.clone();
-        p.push(guildid.to_string());
-        p.push(uuid.hyphenated().to_string());
-        tokio::fs::File::open(p).await.map_err(|e| match e.kind() {
-            std::io::ErrorKind::NotFound => rocket::http::Status::NotFound,
-            _ => {
-                error!("Error when delivering image: {e}");
-                rocket::http::Status::InternalServerError
+        let upload = match tokio::fs::read(tmp_path).await {
+            Ok(b) => b,
+            Err(e) => {
+                error!("Error when reading upload: {e}");
+                return Rsp::err(dem_types::error::Error::Internal, None);
             }
-        })?
-    };
-    let metadata_file = {
-        let mut p = store.base_path.clone();
-        p.push(guildid.to_string());
-        p.push(format!("{}.json", uuid.hyphenated()));
-        tokio::fs::File::open(p)
-            .await
-            .map_err(|e| {
-                error!("Error when finding image metadata: {e}");
-                rocket::http::Status::InternalServerError
-            })?
-            .into_std()
-            .await
-    };
+        };
+        let bytes = processed.clone().unwrap_or_else(|| upload.clone());
+        // The original is stored too when the upload was optimized
+        let original_size = processed.as_ref().map_or(0, |_| upload.len() as u64);
+        let reservation = QuotaReservation {
+            uuid,
+            user_id: uploader_id,
+            bytes: bytes.len() as u64 + original_size,
+        };
+        if let Err(e) = store.reserve_quota(guildid, &reservation).await {
+            error!("Error when reserving quota: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
 
-    let metadata: ImageData =
-        tokio::task::spawn_blocking(|| serde_json::from_reader(metadata_file))
-            .await
-            .map_err(|e| {
-                error!("Error when reading image's metadata: {e}");
-                rocket::http::Status::InternalServerError
-            })?
-            .map_err(|e| {
-                error!("Error when reading image's metadata: {e}");
-                rocket::http::Status::InternalServerError
-            })?;
+        let res = async {
+            let images = match store.list(guildid).await {
+                Ok(i) => i,
+                Err(e) => {
+                    error!("Reading image store: {e}");
+                    return Rsp::err(dem_types::error::Error::Internal, None);
+                }
+            };
+            // Reserved before listing, concurrent uploads see each other here
+            let pending = match store.quota_reservations(guildid).await {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("Error when reading quota reservations: {e}");
+                    return Rsp::err(dem_types::error::Error::Internal, None);
+                }
+            };
+            let (mut guild_usage, mut user_usage) =
+                store.quotas.usage(images.values(), uploader_id);
+            for other in pending.iter().filter(|r| r.uuid != uuid) {
+                guild_usage.items += 1;
+                guild_usage.bytes += other.bytes;
+                if other.user_id == uploader_id {
+                    user_usage.items += 1;
+                    user_usage.bytes += other.bytes;
+                }
+            }
+            if let Err(reason) =
+                crate::quota::Quotas::check(&guild_usage, &user_usage, reservation.bytes)
+            {
+                return Rsp::err(dem_types::error::Error::QuotaExceeded, Some(reason));
+            }
 
-    let content_type = metadata.image_type.to_content_type();
-    let mut cache = store.cache.write().await;
-    if let Some(m) = cache.get_mut(&guildid) {
-        m.insert(uuid, metadata);
-    } else {
-        cache.push(guildid, {
-            let mut hm: std::collections::HashMap<uuid::Uuid, ImageData, fxhash::FxBuildHasher> =
-                Default::default();
-            hm.insert(uuid, metadata);
-            hm
-        });
+            metadata.uploader_id = Some(uploader_id);
+            metadata.uploader_name = Some(uploader_name);
+            metadata.created_at = unix_now();
+            metadata.size = bytes.len() as u64;
+            metadata.original_size = original_size;
+            metadata.dimensions = metadata.image_type.dimensions(&bytes);
+            let image_type = metadata.image_type.clone();
+            (metadata.frames, metadata.hashes) = tokio::task::spawn_blocking(move || {
+                (
+                    image_type.frame_count(&bytes),
+                    crate::dedup::hash_image(&bytes).unwrap_or_default(),
+                )
+            })
+            .await
+            .unwrap_or_default();
+            let duplicates = find_duplicates(logic, store, guildid, &metadata.hashes).await;
+            let put = match processed {
+                Some(data) => futures::try_join!(
+                    store
+                        .blobs
+                        .put(&ImageStore::original_key(guildid, &uuid), upload),
+                    store
+                        .blobs
+                        .put(&ImageStore::image_key(guildid, &uuid), data),
+                )
+                .map(|_| ()),
+                None => {
+                    store
+                        .blobs
+                        .put(&ImageStore::image_key(guildid, &uuid), upload)
+                        .await
+                }
+            };
+            if let Err(e) = put {
+                error!("Error when storing image: {e}");
+                if let Err(e) = store.delete(guildid, &uuid).await {
+                    error!("Error when cleaning up a failed upload: {e}");
+                }
+                return Rsp::err(
+                    dem_types::error::Error::Internal,
+                    Some("Error when trying to store file".to_string()),
+                );
+            }
+
+            if let Err(e) = store.write_metadata(guildid, uuid, metadata).await {
+                error!("Error when writing image metadata: {e}");
+                if let Err(e) = store.delete(guildid, &uuid).await {
+                    error!("Error when cleaning up a failed upload: {e}");
+                }
+                return Rsp::err(
+                    dem_types::error::Error::Internal,
+                    Some("Error when trying to store file".to_string()),
+                );
+            }
+
+            Rsp::ok(dem_types::api::UploadResult {
+                uuid: uuid.hyphenated().to_string(),
+                duplicates,
+            })
+        }
+        .await;
+        // Stored images are counted from the index from now on
+        if let Err(e) = store.release_quota(guildid, &reservation).await {
+            error!("Error when releasing quota reservation: {e}");
+        }
+        res
+    }
+    .await;
+    // Only accepted uploads count in the window
+    if window.is_some() && !matches!(res, Rsp::Ok(_)) {
+        if let Err(e) = store.release_upload(guildid, uploader_id).await {
+            error!("Error when counting uploads: {e}");
+        }
     }
+    res
+}
+
+/// Images of the store and live emojis of the guild looking like `hashes`
+async fn find_duplicates(
+    logic: &crate::discord::Logic,
+    store: &ImageStore,
+    guildid: u64,
+    hashes: &[u64],
+) -> Vec<dem_types::api::Duplicate> {
+    use dem_types::api::{ContentRef, ContentSource, Duplicate};
 
-    Ok((content_type, file))
+    if hashes.is_empty() {
+        return Vec::new();
+    }
+    let mut duplicates = Vec::new();
+    match store.list(guildid).await {
+        Ok(images) => duplicates.extend(images.iter().filter_map(|(uuid, data)| {
+            let similarity = crate::dedup::similarity(hashes, &data.hashes);
+            (similarity >= crate::dedup::SIMILARITY_THRESHOLD).then(|| Duplicate {
+                item: ContentRef {
+                    source: ContentSource::Store,
+                    id: uuid.hyphenated().to_string(),
+                    name: data.name.clone(),
+                },
+                similarity,
+            })
+        })),
+        Err(e) => error!("Reading image store: {e}"),
+    }
+    for (emoji, emoji_hashes) in crate::dedup::live_emoji_hashes(logic, guildid).await {
+        let similarity = crate::dedup::similarity(hashes, &emoji_hashes);
+        if similarity >= crate::dedup::SIMILARITY_THRESHOLD {
+            duplicates.push(Duplicate {
+                item: ContentRef {
+                    source: ContentSource::Emoji,
+                    id: emoji.id.to_string(),
+                    name: emoji.name,
+                },
+                similarity,
+            });
+        }
+    }
+    duplicates.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    duplicates
 }
 
-#[derive(
-    Debug, Clone, Copy, serde::Deserialize, serde::Serialize, PartialEq, Eq, PartialOrd, Ord,
-)]
-pub struct ImageRating {
-    pub racy: Rating,
-    pub adult: Rating,
-    pub spoof: Rating,
-    pub violance: Rating,
-    pub medical: Rating,
-}
-
-impl ImageRating {
-    const MIN: ImageRating = ImageRating {
-        racy: Rating::Unknown,
-        adult: Rating::Possible,
-        spoof: Rating::Unknown,
-        violance: Rating::Possible,
-        medical: Rating::Possible,
+#[openapi]
+#[post("/guild/<guildid>/store/<uuid>/publish")]
+pub async fn publish_to_guild(
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    user: crate::auth::User,
+    guildid: u64,
+    uuid: uuid::Uuid,
+) -> Rsp<dem_types::api::PublishedItem> {
+    if !logic
+        .user_has_permission(
+            &user.session,
+            guildid,
+            crate::discord::MANAGE_EMOJIS_AND_STICKERS,
+        )
+        .await
+    {
+        return Rsp::err(
+            dem_types::error::Error::Unauthorized,
+            Some("You are not in the guild or don't have permission to do so".to_string()),
+        );
+    }
+
+    let mut metadata = match store.read_metadata(guildid, &uuid).await {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Image not found".to_string()),
+            )
+        }
+        Err(e) => {
+            error!("Error when reading image's metadata: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+    match metadata.status {
+        ProposalStatus::Approved => {}
+        ProposalStatus::Published => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Image already published".to_string()),
+            )
+        }
+        ProposalStatus::Pending | ProposalStatus::Quarantined | ProposalStatus::Rejected => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Image has not been approved".to_string()),
+            )
+        }
+    }
+
+    let capacity = match logic.get_guild(guildid) {
+        Some(g) => g.capacity(),
+        None => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Unknown guild".to_string()),
+            )
+        }
+    };
+    let slots = match (metadata.kind, &metadata.image_type) {
+        (ImageKind::Emoji, ImageType::Gif) => capacity.animated_emojis,
+        (ImageKind::Emoji, _) => capacity.static_emojis,
+        (ImageKind::Sticker, _) => capacity.stickers,
+    };
+    if slots.available == 0 {
+        return Rsp::err(
+            dem_types::error::Error::InvalidRequest,
+            Some("The guild has no slot left for this image".to_string()),
+        );
+    }
+
+    let bytes = match store.read_image(guildid, &uuid).await {
+        Ok(b) => b,
+        Err(e) => {
+            error!("Error when reading stored image: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+
+    let published = match metadata.kind {
+        ImageKind::Emoji => logic
+            .create_guild_emoji(
+                guildid,
+                &metadata.name,
+                &metadata.image_type.to_content_type(),
+                &bytes,
+            )
+            .await
+            .map(|emoji| {
+                metadata.emoji_id = Some(emoji.id);
+                if let Some(mut guild) = logic.guilds.get_mut(&guildid) {
+                    guild.emojis.push(emoji.clone());
+                }
+                dem_types::api::PublishedItem {
+                    emoji: Some(emoji),
+                    sticker: None,
+                }
+            }),
+        ImageKind::Sticker => logic
+            .create_guild_sticker(
+                guildid,
+                &metadata.name,
+                metadata.description.as_deref().unwrap_or_default(),
+                metadata.tags.as_deref().unwrap_or_default(),
+                &metadata.image_type.file_name(&metadata.name),
+                &metadata.image_type.to_content_type(),
+                bytes,
+            )
+            .await
+            .map(|sticker| {
+                metadata.sticker_id = Some(sticker.id);
+                if let Some(mut guild) = logic.guilds.get_mut(&guildid) {
+                    guild.stickers.push(sticker.clone());
+                }
+                dem_types::api::PublishedItem {
+                    emoji: None,
+                    sticker: Some(sticker),
+                }
+            }),
+    };
+    let published = match published {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Error when publishing to guild: {e}");
+            return Rsp::err(dem_types::error::Error::DiscordAPI, Some(format!("{e}")));
+        }
     };
+
+    metadata.status = ProposalStatus::Published;
+    if let Err(e) = store.write_metadata(guildid, uuid, metadata).await {
+        error!("Error when writing image metadata: {e}");
+    }
+
+    Rsp::ok(published)
 }
 
-#[derive(
-    Debug, Clone, Copy, serde::Deserialize, serde::Serialize, PartialEq, Eq, PartialOrd, Ord,
-)]
-pub enum Rating {
-    #[serde(rename = "UNKNOWN")]
-    Unknown = 5,
-    #[serde(rename = "VERY_UNLIKELY")]
-    VeryUnlikely = 4,
-    #[serde(rename = "UNLIKELY")]
-    Unlikely = 3,
-    #[serde(rename = "POSSIBLE")]
-    Possible = 2,
-    #[serde(rename = "LIKELY")]
-    Likely = 1,
-    #[serde(rename = "VERY_LIKELY")]
-    VeryLikely = 0,
+/// How much of the store quotas the guild and the current user use
+#[openapi]
+#[get("/guild/<guildid>/store/usage")]
+pub async fn store_usage(
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    user: crate::auth::User,
+    guildid: u64,
+) -> Rsp<dem_types::api::StoreUsage> {
+    let user_id = match logic
+        .user_cache
+        .write()
+        .await
+        .get(&user.session)
+        .filter(|u| u.guilds.contains_key(&guildid))
+        .map(|u| u.user_id)
+    {
+        Some(id) => id,
+        None => return Rsp::err(dem_types::error::Error::Unauthorized, None),
+    };
+    let images = match store.list(guildid).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Reading image store: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+    let uploads = match store.uploads_in_window(guildid, user_id).await {
+        Ok(u) => u,
+        Err(e) => {
+            error!("Error when counting uploads: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+    let (guild, user) = store.quotas.usage(images.values(), user_id);
+    Rsp::ok(dem_types::api::StoreUsage {
+        guild,
+        user,
+        uploads,
+        max_uploads: store.quotas.uploads_per_window,
+        window_secs: store.quotas.window_secs,
+    })
+}
+
+/// The uploader of an image or a moderator of its guild
+async fn can_manage_image(
+    logic: &crate::discord::Logic,
+    user: &crate::auth::User,
+    guildid: u64,
+    metadata: &ImageData,
+) -> bool {
+    let is_uploader = metadata.uploader_id.is_some()
+        && logic
+            .user_cache
+            .write()
+            .await
+            .get(&user.session)
+            .filter(|u| u.guilds.contains_key(&guildid))
+            .map(|u| u.user_id)
+            == metadata.uploader_id;
+    is_uploader
+        || logic
+            .user_has_permission(
+                &user.session,
+                guildid,
+                crate::discord::MANAGE_EMOJIS_AND_STICKERS,
+            )
+            .await
+}
+
+/// Remove an image and its metadata from the store.
+/// Published emojis and stickers stay in the guild.
+#[openapi]
+#[delete("/guild/<guildid>/store/<uuid>")]
+pub async fn store_delete(
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    user: crate::auth::User,
+    guildid: u64,
+    uuid: uuid::Uuid,
+) -> Rsp<()> {
+    let metadata = match store.read_metadata(guildid, &uuid).await {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Image not found".to_string()),
+            )
+        }
+        Err(e) => {
+            error!("Error when reading image's metadata: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+    if !can_manage_image(logic, &user, guildid, &metadata).await {
+        return Rsp::err(
+            dem_types::error::Error::Unauthorized,
+            Some("Only the uploader or a moderator can do so".to_string()),
+        );
+    }
+    if let Err(e) = store.delete(guildid, &uuid).await {
+        error!("Error when deleting image: {e}");
+        return Rsp::err(dem_types::error::Error::Internal, None);
+    }
+    Rsp::ok(())
+}
+
+/// Rename an image of the store, or change the description and tags of a sticker
+#[openapi]
+#[patch("/guild/<guildid>/store/<uuid>", data = "<patch>")]
+pub async fn store_patch(
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    user: crate::auth::User,
+    guildid: u64,
+    uuid: uuid::Uuid,
+    patch: rocket::serde::json::Json<dem_types::api::StoredImagePatch>,
+) -> Rsp<ImageDataApi> {
+    let mut metadata = match store.read_metadata(guildid, &uuid).await {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Image not found".to_string()),
+            )
+        }
+        Err(e) => {
+            error!("Error when reading image's metadata: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+    if !can_manage_image(logic, &user, guildid, &metadata).await {
+        return Rsp::err(
+            dem_types::error::Error::Unauthorized,
+            Some("Only the uploader or a moderator can do so".to_string()),
+        );
+    }
+    if metadata.status == ProposalStatus::Published {
+        return Rsp::err(
+            dem_types::error::Error::InvalidRequest,
+            Some("Published images are edited through the guild".to_string()),
+        );
+    }
+
+    let patch = patch.into_inner();
+    match metadata.kind {
+        ImageKind::Emoji => {
+            if patch.description.is_some() || patch.tags.is_some() {
+                return Rsp::err(
+                    dem_types::error::Error::InvalidRequest,
+                    Some("Emojis have no description or tags".to_string()),
+                );
+            }
+            if let Some(name) = &patch.name {
+                if !is_valid_emoji_name(name) {
+                    return Rsp::err(
+                        dem_types::error::Error::InvalidRequest,
+                        Some("Invalid name".to_string()),
+                    );
+                }
+            }
+        }
+        ImageKind::Sticker => {
+            if patch
+                .name
+                .as_ref()
+                .map_or(false, |n| !(2..=30).contains(&n.len()))
+            {
+                return Rsp::err(
+                    dem_types::error::Error::InvalidRequest,
+                    Some("Invalid name".to_string()),
+                );
+            }
+            if patch
+                .description
+                .as_ref()
+                .map_or(false, |d| !(2..=100).contains(&d.len()))
+            {
+                return Rsp::err(
+                    dem_types::error::Error::InvalidRequest,
+                    Some("Invalid description".to_string()),
+                );
+            }
+            if patch
+                .tags
+                .as_ref()
+                .map_or(false, |t| t.is_empty() || t.len() > 200)
+            {
+                return Rsp::err(
+                    dem_types::error::Error::InvalidRequest,
+                    Some("Invalid tags".to_string()),
+                );
+            }
+        }
+    }
+
+    if let Some(name) = patch.name {
+        metadata.name = name;
+    }
+    if let Some(description) = patch.description {
+        metadata.description = Some(description);
+    }
+    if let Some(tags) = patch.tags {
+        metadata.tags = Some(tags);
+    }
+    let api = ImageDataApi::new(&uuid, &metadata);
+    if let Err(e) = store.write_metadata(guildid, uuid, metadata).await {
+        error!("Error when writing image metadata: {e}");
+        return Rsp::err(dem_types::error::Error::Internal, None);
+    }
+    Rsp::ok(api)
+}
+
+#[get("/<guildid>/<uuid>")]
+pub async fn image_serve(
+    uuid: uuid::Uuid,
+    guildid: u64,
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    user: Option<crate::auth::User>,
+) -> Result<(rocket::http::ContentType, Vec<u8>), rocket::http::Status> {
+    let metadata = store
+        .read_metadata(guildid, &uuid)
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => rocket::http::Status::NotFound,
+            _ => {
+                error!("Error when reading image's metadata: {e}");
+                rocket::http::Status::InternalServerError
+            }
+        })?;
+    // Only moderators get to see what is in quarantine
+    if metadata.status == ProposalStatus::Quarantined {
+        let moderator = match user {
+            Some(user) => {
+                logic
+                    .user_has_permission(
+                        &user.session,
+                        guildid,
+                        crate::discord::MANAGE_EMOJIS_AND_STICKERS,
+                    )
+                    .await
+            }
+            None => false,
+        };
+        if !moderator {
+            return Err(rocket::http::Status::NotFound);
+        }
+    }
+    let image = store
+        .read_image(guildid, &uuid)
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => rocket::http::Status::NotFound,
+            _ => {
+                error!("Error when delivering image: {e}");
+                rocket::http::Status::InternalServerError
+            }
+        })?;
+
+    let content_type = metadata.image_type.to_content_type();
+
+    Ok((content_type, image))
 }
 
 #[derive(Clone, Debug, JsonSchema, serde::Deserialize, serde::Serialize)]
@@ -260,93 +1645,580 @@ pub struct ImageDataApi {
     uuid: String,
     name: String,
     image_type: ImageType,
+    kind: ImageKind,
+    description: Option<String>,
+    tags: Option<String>,
+    emoji_id: Option<u64>,
+    sticker_id: Option<u64>,
+    status: ProposalStatus,
+    uploader_id: Option<u64>,
+    uploader_name: Option<String>,
+    original_filename: Option<String>,
+    /// Seconds since the unix epoch
+    created_at: u64,
+    updated_at: u64,
+    /// Bytes
+    size: u64,
+    dimensions: Option<(u32, u32)>,
+    frames: Option<u32>,
+    rating: Option<crate::moderation::ImageRating>,
 }
 
+impl ImageDataApi {
+    fn new(uuid: &uuid::Uuid, data: &ImageData) -> Self {
+        ImageDataApi {
+            uuid: uuid.to_string(),
+            name: data.name.clone(),
+            image_type: data.image_type.clone(),
+            kind: data.kind,
+            description: data.description.clone(),
+            tags: data.tags.clone(),
+            emoji_id: data.emoji_id,
+            sticker_id: data.sticker_id,
+            status: data.status,
+            uploader_id: data.uploader_id,
+            uploader_name: data.uploader_name.clone(),
+            original_filename: data.original_filename.clone(),
+            created_at: data.created_at,
+            updated_at: data.updated_at,
+            size: data.size,
+            dimensions: data.dimensions,
+            frames: data.frames,
+            rating: data.rating,
+        }
+    }
+}
+
+/// The guild's stored images, `name` matches any part of the name ignoring case
 #[openapi]
-#[get("/uploaded/<guildid>/emojis")]
+#[get("/uploaded/<guildid>/emojis?<status>&<kind>&<uploader>&<name>&<sort>&<desc>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn image_list(
     store: &rocket::State<ImageStore>,
     logic: &rocket::State<crate::discord::Logic>,
     user: crate::auth::User,
     guildid: u64,
+    status: Option<ProposalStatus>,
+    kind: Option<ImageKind>,
+    uploader: Option<u64>,
+    name: Option<&str>,
+    sort: Option<SortBy>,
+    desc: Option<bool>,
 ) -> Rsp<Vec<ImageDataApi>> {
     if logic
         .user_cache
         .write()
         .await
-        .get(&user.token)
+        .get(&user.session)
         .map(|u| u.guilds.get(&guildid).is_some())
         .unwrap_or_default()
     {
-        if let Some(u) = store.cache.write().await.get(&guildid) {
-            debug!("Image store cache hit");
-            return Rsp::ok(
-                u.iter()
-                    .map(|(uuid, data)| ImageDataApi {
-                        uuid: uuid.to_string(),
-                        name: data.name.clone(),
-                        image_type: data.image_type.clone(),
-                    })
-                    .collect::<Vec<_>>(),
-            );
-        }
-        use futures::StreamExt;
-        let files = tokio::fs::read_dir({
-            let mut p = store.base_path.clone();
-            p.push(guildid.to_string());
-            p
-        })
-        .await;
-        if let Err(e) = files {
+        let filter = ImageFilter {
+            status,
+            kind,
+            uploader_id: uploader,
+            name,
+            sort: sort.unwrap_or_default(),
+            descending: desc.unwrap_or_default(),
+        };
+        let images = match store.query(guildid, &filter).await {
+            Ok(i) => i,
+            Err(e) => {
+                error!("Reading image store: {e}");
+                return Rsp::err(dem_types::error::Error::Internal, None);
+            }
+        };
+        let emojis = images
+            .iter()
+            .map(|(uuid, data)| ImageDataApi::new(uuid, data))
+            .collect::<Vec<_>>();
+
+        Rsp::ok(emojis)
+    } else {
+        Rsp::err(dem_types::error::Error::Unauthorized, None)
+    }
+}
+
+/// Pairs of look-alike images among the guild's store and live emojis
+#[openapi]
+#[get("/guild/<guildid>/duplicates")]
+pub async fn duplicate_report(
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    user: crate::auth::User,
+    guildid: u64,
+) -> Rsp<Vec<dem_types::api::DuplicatePair>> {
+    use dem_types::api::{ContentRef, ContentSource, DuplicatePair};
+
+    if !logic
+        .user_cache
+        .write()
+        .await
+        .get(&user.session)
+        .map(|u| u.guilds.get(&guildid).is_some())
+        .unwrap_or_default()
+    {
+        return Rsp::err(dem_types::error::Error::Unauthorized, None);
+    }
+    let images = match store.list(guildid).await {
+        Ok(i) => i,
+        Err(e) => {
             error!("Reading image store: {e}");
             return Rsp::err(dem_types::error::Error::Internal, None);
         }
-        let files = tokio_stream::wrappers::ReadDirStream::new(files.unwrap());
-        let emojis = files
-            .filter_map(|f| async { f.ok() })
-            .filter_map(|f| async { f.file_name().to_str().map(|n| (n.to_string(), f)) })
-            .filter_map(|(n, f)| async move {
-                n.split('.')
-                    .nth_back(0)
-                    .and_then(|ext| if ext == "json" { Some(()) } else { None })
-                    .map(|_| (n.to_string(), f))
-            })
-            .filter_map(|(n, f)| async move {
-                Some((
-                    n,
-                    tokio::task::spawn_blocking(move || {
-                        let file = std::fs::File::open(f.path()).unwrap();
-                        serde_json::from_reader::<_, ImageData>(file).unwrap()
-                    })
+    };
+
+    // The emoji id is kept to not report a published image as a duplicate of its own emoji
+    let mut items: Vec<(ContentRef, Vec<u64>, Option<u64>)> = Vec::new();
+    for (uuid, mut data) in images {
+        if data.status == ProposalStatus::Rejected {
+            continue;
+        }
+        // Uploads from before hashing existed get their hashes now
+        if data.hashes.is_empty() && !matches!(data.image_type, ImageType::Lottie) {
+            if let Ok(bytes) = store.read_image(guildid, &uuid).await {
+                data.hashes = tokio::task::spawn_blocking(move || crate::dedup::hash_image(&bytes))
                     .await
-                    .unwrap(),
-                ))
-            })
-            .map(|(n, img)| ImageDataApi {
-                name: img.name,
-                image_type: img.image_type,
-                uuid: n.split('.').next().unwrap().to_string(),
-            })
-            .collect::<Vec<_>>()
-            .await;
-        let emoji_for_cache = emojis.clone().into_iter().map(|d| {
-            (
-                uuid::Uuid::parse_str(&d.uuid).unwrap(),
-                ImageData {
-                    image_type: d.image_type,
-                    name: d.name,
-                },
-            )
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default();
+                if !data.hashes.is_empty() {
+                    if let Err(e) = store.write_metadata(guildid, uuid, data.clone()).await {
+                        error!("Error when writing image metadata: {e}");
+                    }
+                }
+            }
+        }
+        items.push((
+            ContentRef {
+                source: ContentSource::Store,
+                id: uuid.hyphenated().to_string(),
+                name: data.name,
+            },
+            data.hashes,
+            data.emoji_id,
+        ));
+    }
+    for (emoji, hashes) in crate::dedup::live_emoji_hashes(logic, guildid).await {
+        items.push((
+            ContentRef {
+                source: ContentSource::Emoji,
+                id: emoji.id.to_string(),
+                name: emoji.name,
+            },
+            hashes,
+            Some(emoji.id),
+        ));
+    }
+
+    let mut pairs = Vec::new();
+    for (i, (first, first_hashes, first_emoji)) in items.iter().enumerate() {
+        for (second, second_hashes, second_emoji) in &items[i + 1..] {
+            if first_emoji.is_some() && first_emoji == second_emoji {
+                continue;
+            }
+            let similarity = crate::dedup::similarity(first_hashes, second_hashes);
+            if similarity >= crate::dedup::SIMILARITY_THRESHOLD {
+                pairs.push(DuplicatePair {
+                    first: first.clone(),
+                    second: second.clone(),
+                    similarity,
+                });
+            }
+        }
+    }
+    pairs.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    Rsp::ok(pairs)
+}
+
+#[derive(Clone, Debug, JsonSchema, serde::Deserialize, serde::Serialize)]
+pub struct Proposal {
+    image: ImageDataApi,
+    upvotes: u32,
+    downvotes: u32,
+    /// `1` for an upvote, `-1` for a downvote
+    own_vote: Option<i8>,
+}
+
+fn votes_key(guildid: u64, uuid: &uuid::Uuid) -> String {
+    format!("dem:votes:{guildid}:{}", uuid.hyphenated())
+}
+
+async fn proposal_votes(
+    con: &mut rocket_db_pools::deadpool_redis::Connection,
+    guildid: u64,
+    uuid: &uuid::Uuid,
+    user_id: u64,
+) -> Result<(u32, u32, Option<i8>), rocket_db_pools::deadpool_redis::redis::RedisError> {
+    let votes = Cmd::hgetall(votes_key(guildid, uuid))
+        .query_async::<_, std::collections::HashMap<u64, i8>>(con)
+        .await?;
+    let upvotes = votes.values().filter(|&&v| v > 0).count() as u32;
+    let downvotes = votes.values().filter(|&&v| v < 0).count() as u32;
+    Ok((upvotes, downvotes, votes.get(&user_id).copied()))
+}
+
+#[openapi]
+#[get("/guild/<guildid>/proposals")]
+pub async fn proposal_list(
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    db: &rocket::State<crate::DemDb>,
+    user: crate::auth::User,
+    guildid: u64,
+) -> Rsp<Vec<Proposal>> {
+    let user_id = match logic
+        .user_cache
+        .write()
+        .await
+        .get(&user.session)
+        .filter(|u| u.guilds.contains_key(&guildid))
+        .map(|u| u.user_id)
+    {
+        Some(id) => id,
+        None => return Rsp::err(dem_types::error::Error::Unauthorized, None),
+    };
+    let images = match store.list(guildid).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Reading image store: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+    let mut con = match db.connection().await {
+        Some(c) => c,
+        None => return Rsp::err(dem_types::error::Error::Internal, None),
+    };
+    let mut proposals = Vec::with_capacity(images.len());
+    for (uuid, data) in images.iter().filter(|(_, d)| {
+        !matches!(
+            d.status,
+            ProposalStatus::Published | ProposalStatus::Quarantined
+        )
+    }) {
+        let (upvotes, downvotes, own_vote) =
+            match proposal_votes(&mut con, guildid, uuid, user_id).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Error when comunicating with redis db: {e}");
+                    return Rsp::err(dem_types::error::Error::Internal, None);
+                }
+            };
+        proposals.push(Proposal {
+            image: ImageDataApi::new(uuid, data),
+            upvotes,
+            downvotes,
+            own_vote,
         });
-        store.cache.write().await.push(
+    }
+    Rsp::ok(proposals)
+}
+
+/// Vote on a pending proposal, `1` is an upvote, `-1` a downvote and `0` removes the vote
+#[openapi]
+#[put("/guild/<guildid>/store/<uuid>/vote?<vote>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn proposal_vote(
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    db: &rocket::State<crate::DemDb>,
+    user: crate::auth::User,
+    guildid: u64,
+    uuid: uuid::Uuid,
+    vote: i8,
+) -> Rsp<Proposal> {
+    if !(-1..=1).contains(&vote) {
+        return Rsp::err(
+            dem_types::error::Error::InvalidRequest,
+            Some("Invalid vote".to_string()),
+        );
+    }
+    let user_id = match logic
+        .user_cache
+        .write()
+        .await
+        .get(&user.session)
+        .filter(|u| u.guilds.contains_key(&guildid))
+        .map(|u| u.user_id)
+    {
+        Some(id) => id,
+        None => return Rsp::err(dem_types::error::Error::Unauthorized, None),
+    };
+    let mut metadata = match store.read_metadata(guildid, &uuid).await {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Image not found".to_string()),
+            )
+        }
+        Err(e) => {
+            error!("Error when reading image's metadata: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+    if metadata.status != ProposalStatus::Pending {
+        return Rsp::err(
+            dem_types::error::Error::InvalidRequest,
+            Some("Voting is closed for this image".to_string()),
+        );
+    }
+
+    let mut con = match db.connection().await {
+        Some(c) => c,
+        None => return Rsp::err(dem_types::error::Error::Internal, None),
+    };
+    let key = votes_key(guildid, &uuid);
+    let res = if vote == 0 {
+        Cmd::hdel(&key, user_id)
+            .query_async::<_, ()>(&mut con)
+            .await
+    } else {
+        Cmd::hset(&key, user_id, vote)
+            .query_async::<_, ()>(&mut con)
+            .await
+    };
+    let votes = match res {
+        Ok(()) => proposal_votes(&mut con, guildid, &uuid, user_id).await,
+        Err(e) => Err(e),
+    };
+    let (upvotes, downvotes, own_vote) = match votes {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Error when comunicating with redis db: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+
+    if let Some(threshold) = store.vote_threshold {
+        if upvotes as i64 - downvotes as i64 >= threshold {
+            // A moderator may have reviewed it since it was read, only a pending one is approved
+            metadata = match store.approve_pending(guildid, &uuid).await {
+                Ok(m) => m,
+                Err(e) => {
+                    error!("Error when approving a proposal: {e}");
+                    return Rsp::err(dem_types::error::Error::Internal, None);
+                }
+            };
+        }
+    }
+
+    Rsp::ok(Proposal {
+        image: ImageDataApi::new(&uuid, &metadata),
+        upvotes,
+        downvotes,
+        own_vote,
+    })
+}
+
+/// Moderator decision on a proposal
+#[openapi]
+#[post("/guild/<guildid>/store/<uuid>/review?<approve>")]
+pub async fn proposal_review(
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    user: crate::auth::User,
+    guildid: u64,
+    uuid: uuid::Uuid,
+    approve: bool,
+) -> Rsp<ImageDataApi> {
+    let moderator_id = match moderator_id(logic, &user, guildid).await {
+        Some(id) => id,
+        None => {
+            return Rsp::err(
+                dem_types::error::Error::Unauthorized,
+                Some("You are not in the guild or don't have permission to do so".to_string()),
+            )
+        }
+    };
+    let mut metadata = match store.read_metadata(guildid, &uuid).await {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Image not found".to_string()),
+            )
+        }
+        Err(e) => {
+            error!("Error when reading image's metadata: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+    match metadata.status {
+        ProposalStatus::Published => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Image already published".to_string()),
+            )
+        }
+        ProposalStatus::Quarantined => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Image is in quarantine".to_string()),
+            )
+        }
+        _ => {}
+    }
+    metadata.status = if approve {
+        ProposalStatus::Approved
+    } else {
+        ProposalStatus::Rejected
+    };
+    metadata
+        .moderation_log
+        .push(ModerationDecision::new(moderator_id, approve));
+    let api = ImageDataApi::new(&uuid, &metadata);
+    if let Err(e) = store.write_metadata(guildid, uuid, metadata).await {
+        error!("Error when writing image metadata: {e}");
+        return Rsp::err(dem_types::error::Error::Internal, None);
+    }
+    Rsp::ok(api)
+}
+
+/// The user id of `user` if they can moderate the guild's store
+async fn moderator_id(
+    logic: &crate::discord::Logic,
+    user: &crate::auth::User,
+    guildid: u64,
+) -> Option<u64> {
+    if !logic
+        .user_has_permission(
+            &user.session,
             guildid,
-            emoji_for_cache
-                .collect::<std::collections::HashMap<uuid::Uuid, ImageData, fxhash::FxBuildHasher>>(
-                ),
+            crate::discord::MANAGE_EMOJIS_AND_STICKERS,
+        )
+        .await
+    {
+        return None;
+    }
+    user_id(logic, user).await
+}
+
+async fn user_id(logic: &crate::discord::Logic, user: &crate::auth::User) -> Option<u64> {
+    logic
+        .user_cache
+        .write()
+        .await
+        .get(&user.session)
+        .map(|u| u.user_id)
+}
+
+#[derive(Clone, Debug, JsonSchema, serde::Deserialize, serde::Serialize)]
+pub struct QuarantinedImage {
+    image: ImageDataApi,
+    rating: Option<crate::moderation::ImageRating>,
+    moderation_log: Vec<ModerationDecision>,
+}
+
+/// Uploads waiting for a moderator because of a borderline rating
+#[openapi]
+#[get("/guild/<guildid>/quarantine")]
+pub async fn quarantine_list(
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    user: crate::auth::User,
+    guildid: u64,
+) -> Rsp<Vec<QuarantinedImage>> {
+    if moderator_id(logic, &user, guildid).await.is_none() {
+        return Rsp::err(
+            dem_types::error::Error::Unauthorized,
+            Some("You are not in the guild or don't have permission to do so".to_string()),
         );
+    }
+    let images = match store.list(guildid).await {
+        Ok(i) => i,
+        Err(e) => {
+            error!("Reading image store: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+    Rsp::ok(
+        images
+            .iter()
+            .filter(|(_, d)| d.status == ProposalStatus::Quarantined)
+            .map(|(uuid, data)| QuarantinedImage {
+                image: ImageDataApi::new(uuid, data),
+                rating: data.rating,
+                moderation_log: data.moderation_log.clone(),
+            })
+            .collect(),
+    )
+}
 
-        Rsp::ok(emojis)
+/// Let a quarantined upload through to the proposals, or reject it
+#[openapi]
+#[post("/guild/<guildid>/quarantine/<uuid>?<approve>")]
+pub async fn quarantine_review(
+    store: &rocket::State<ImageStore>,
+    logic: &rocket::State<crate::discord::Logic>,
+    user: crate::auth::User,
+    guildid: u64,
+    uuid: uuid::Uuid,
+    approve: bool,
+) -> Rsp<ImageDataApi> {
+    let moderator_id = match moderator_id(logic, &user, guildid).await {
+        Some(id) => id,
+        None => {
+            return Rsp::err(
+                dem_types::error::Error::Unauthorized,
+                Some("You are not in the guild or don't have permission to do so".to_string()),
+            )
+        }
+    };
+    let mut metadata = match store.read_metadata(guildid, &uuid).await {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Rsp::err(
+                dem_types::error::Error::InvalidRequest,
+                Some("Image not found".to_string()),
+            )
+        }
+        Err(e) => {
+            error!("Error when reading image's metadata: {e}");
+            return Rsp::err(dem_types::error::Error::Internal, None);
+        }
+    };
+    if metadata.status != ProposalStatus::Quarantined {
+        return Rsp::err(
+            dem_types::error::Error::InvalidRequest,
+            Some("Image is not in quarantine".to_string()),
+        );
+    }
+    metadata.status = if approve {
+        ProposalStatus::Pending
     } else {
-        Rsp::err(dem_types::error::Error::Unauthorized, None)
+        ProposalStatus::Rejected
+    };
+    metadata
+        .moderation_log
+        .push(ModerationDecision::new(moderator_id, approve));
+    let api = ImageDataApi::new(&uuid, &metadata);
+    if let Err(e) = store.write_metadata(guildid, uuid, metadata).await {
+        error!("Error when writing image metadata: {e}");
+        return Rsp::err(dem_types::error::Error::Internal, None);
+    }
+    Rsp::ok(api)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_reservations_round_trip() {
+        let reservation = QuotaReservation {
+            uuid: uuid::Uuid::new_v4(),
+            user_id: 80351110224678912,
+            bytes: 262144,
+        };
+        assert_eq!(
+            QuotaReservation::parse(&reservation.member()),
+            Some(reservation)
+        );
+        assert_eq!(QuotaReservation::parse("not-a-uuid:1:2"), None);
+        assert_eq!(
+            QuotaReservation::parse(&format!("{}:1:2:3", uuid::Uuid::new_v4())),
+            None
+        );
     }
 }
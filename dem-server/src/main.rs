@@ -13,9 +13,17 @@ extern crate tokio;
 extern crate fxhash;
 
 mod api;
+mod api_token;
 mod auth;
+mod blob;
+mod dedup;
 mod discord;
+mod gateway;
 mod image;
+mod manifest;
+mod moderation;
+mod processing;
+mod quota;
 mod retry_middleware;
 
 pub use dem_types::error::{Error, Rsp};
@@ -25,6 +33,16 @@ use rocket_db_pools::{deadpool_redis::Pool, Connection, Database};
 #[database("dem_db")]
 pub struct DemDb(Pool);
 
+impl DemDb {
+    /// Get a connection from the pool for routes that can't use the `Connection` guard
+    pub async fn connection(&self) -> Option<rocket_db_pools::deadpool_redis::Connection> {
+        self.get()
+            .await
+            .map_err(|e| error!("Error when getting a redis connection: {e}"))
+            .ok()
+    }
+}
+
 #[rocket::launch]
 async fn launch() -> _ {
     let tmp_dir: String = rocket::Config::figment()
@@ -38,19 +56,8 @@ async fn launch() -> _ {
         },
     }
     .expect("Unable to create temp_dir");
-    let img_store: String = rocket::Config::figment()
-        .extract_inner("dem.image_store")
-        .expect("You need to specity a image_store location");
-    match tokio::fs::create_dir(&img_store).await {
-        Ok(_) => Ok(()),
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::AlreadyExists => Ok(()),
-            _ => Err(e),
-        },
-    }
-    .expect("Unable to create image store directory");
     rocket::build()
-        .mount("/dev", routes![get_emojis, get_stickers, get_user])
+        .mount("/dev", routes![get_emojis, get_stickers])
         .mount("/store", routes![image::image_serve])
         .mount(
             "/swagger-ui",
@@ -66,8 +73,27 @@ async fn launch() -> _ {
                 api::get_current_user,
                 api::get_guild_emojis,
                 api::get_guild_stickers,
+                api::get_guild_capacity,
+                api::patch_guild_emoji,
+                api::delete_guild_emoji,
+                api::copy_to_guild,
                 image::upload_emoji_to_store,
+                image::upload_sticker_to_store,
                 image::image_list,
+                image::duplicate_report,
+                image::publish_to_guild,
+                image::store_delete,
+                image::store_patch,
+                image::store_usage,
+                image::proposal_list,
+                image::proposal_vote,
+                image::proposal_review,
+                image::quarantine_list,
+                image::quarantine_review,
+                manifest::sync_guild,
+                api_token::create_token,
+                api_token::list_tokens,
+                api_token::revoke_token,
             ],
         )
         .mount(
@@ -89,9 +115,31 @@ async fn launch() -> _ {
                 .map_err(|e| error!("Error when parsing dem config: {e}"))
                 .unwrap(),
         )
-        .manage(image::ImageStore::from_figment(&rocket::Config::figment()))
+        .attach(rocket::fairing::AdHoc::on_shutdown("Gateway", |rocket| {
+            Box::pin(async move {
+                if let Some(logic) = rocket.state::<Logic>() {
+                    logic.shutdown_gateway();
+                }
+            })
+        }))
         .attach(rocket_oauth2::OAuth2::<auth::Discord>::fairing("discord"))
         .attach(DemDb::init())
+        .attach(rocket::fairing::AdHoc::try_on_ignite(
+            "Image store",
+            |rocket| async move {
+                let db = match DemDb::fetch(&rocket) {
+                    Some(db) => db.0.clone(),
+                    None => return Err(rocket),
+                };
+                let store = image::ImageStore::from_figment(rocket.figment(), db);
+                match store.import_sidecars().await {
+                    Ok(0) => {}
+                    Ok(n) => info!("Imported {n} image metadata files in the index"),
+                    Err(e) => error!("Error when importing image metadata files: {e}"),
+                }
+                Ok(rocket.manage(store))
+            },
+        ))
 }
 
 #[get("/get_emojis?<guildid>")]
@@ -107,11 +155,6 @@ async fn get_emojis(
     )
 }
 
-#[get("/user")]
-async fn get_user(user: auth::User) -> String {
-    user.token
-}
-
 #[get("/get_stickers?<guildid>")]
 async fn get_stickers(
     logic: &rocket::State<Logic>,
@@ -0,0 +1,718 @@
+use crate::{Error, Rsp};
+use dem_types::api::{
+    ManifestEmoji, ManifestSticker, SyncAction, SyncItem, SyncKind, SyncRequest, SyncResult,
+};
+use dem_types::discord::{EmojiItem, StickerItem};
+use std::collections::HashSet;
+
+/// Images at least this similar are considered unchanged, stricter than duplicate detection
+const SAME_IMAGE: f32 = 0.97;
+/// Order in which the plan is applied, deletions first to free the slots
+const APPLY_ORDER: [SyncAction; 5] = [
+    SyncAction::Delete,
+    SyncAction::Replace,
+    SyncAction::Rename,
+    SyncAction::Update,
+    SyncAction::Create,
+];
+
+/// An emoji of the manifest, ready to be sent to Discord
+struct WantedEmoji {
+    name: String,
+    id: Option<u64>,
+    roles: Vec<u64>,
+    image: crate::processing::Processed,
+    hashes: Vec<u64>,
+}
+
+/// A sticker of the manifest, ready to be sent to Discord
+struct WantedSticker {
+    name: String,
+    id: Option<u64>,
+    description: Option<String>,
+    tags: String,
+    image_type: crate::image::ImageType,
+    data: Vec<u8>,
+    image: Option<StickerImage>,
+}
+
+/// What is compared to tell whether a sticker changed
+enum StickerImage {
+    /// Perceptual hashes of a PNG or an APNG
+    Raster(Vec<u64>),
+    /// Lottie animations are compared as JSON
+    Lottie(serde_json::Value),
+}
+
+impl StickerImage {
+    fn new(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"\x89PNG") {
+            crate::dedup::hash_image(bytes).map(Self::Raster)
+        } else {
+            serde_json::from_slice(bytes).ok().map(Self::Lottie)
+        }
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Raster(a), Self::Raster(b)) => crate::dedup::similarity(a, b) >= SAME_IMAGE,
+            (Self::Lottie(a), Self::Lottie(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// A change of the plan, with the manifest entry and the live item it is about
+struct Step {
+    item: SyncItem,
+    wanted: Option<usize>,
+    live: Option<usize>,
+}
+
+async fn prepare_emojis(emojis: Vec<ManifestEmoji>) -> Result<Vec<WantedEmoji>, String> {
+    let mut names = HashSet::with_capacity(emojis.len());
+    let mut wanted = Vec::with_capacity(emojis.len());
+    for emoji in emojis {
+        if !crate::image::is_valid_emoji_name(&emoji.name) {
+            return Err(format!("{}: invalid name", emoji.name));
+        }
+        if !names.insert(emoji.name.clone()) {
+            return Err(format!("{}: listed twice", emoji.name));
+        }
+        let bytes = base64::decode(&emoji.image)
+            .map_err(|e| format!("{}: invalid base64: {e}", emoji.name))?;
+        let (image, hashes) = tokio::task::spawn_blocking(move || {
+            let processed = crate::processing::normalize_emoji(&bytes)?;
+            let hashes = crate::dedup::hash_image(&processed.data).unwrap_or_default();
+            Ok::<_, crate::processing::ProcessingError>((processed, hashes))
+        })
+        .await
+        .map_err(|e| format!("{}: {e}", emoji.name))?
+        .map_err(|e| format!("{}: {e}", emoji.name))?;
+        wanted.push(WantedEmoji {
+            name: emoji.name,
+            id: emoji.id,
+            roles: emoji.roles,
+            image,
+            hashes,
+        });
+    }
+    Ok(wanted)
+}
+
+fn prepare_stickers(stickers: Vec<ManifestSticker>) -> Result<Vec<WantedSticker>, String> {
+    let mut names = HashSet::with_capacity(stickers.len());
+    let mut wanted = Vec::with_capacity(stickers.len());
+    for sticker in stickers {
+        if !(2..=30).contains(&sticker.name.len()) {
+            return Err(format!("{}: invalid name", sticker.name));
+        }
+        if sticker
+            .description
+            .as_ref()
+            .map_or(false, |d| !(2..=100).contains(&d.len()))
+        {
+            return Err(format!("{}: invalid description", sticker.name));
+        }
+        if sticker.tags.is_empty() || sticker.tags.len() > 200 {
+            return Err(format!("{}: invalid tags", sticker.name));
+        }
+        if !names.insert(sticker.name.clone()) {
+            return Err(format!("{}: listed twice", sticker.name));
+        }
+        let data = base64::decode(&sticker.image)
+            .map_err(|e| format!("{}: invalid base64: {e}", sticker.name))?;
+        let image_type =
+            crate::image::sticker_type(&data).map_err(|e| format!("{}: {e}", sticker.name))?;
+        wanted.push(WantedSticker {
+            image: StickerImage::new(&data),
+            name: sticker.name,
+            id: sticker.id,
+            description: sticker.description,
+            tags: sticker.tags,
+            image_type,
+            data,
+        });
+    }
+    Ok(wanted)
+}
+
+/// Pair the manifest entries with the live items, by pinned id, then by name, then by image.
+/// Returns the index of the live item of each entry.
+fn pair(
+    wanted: &[(Option<u64>, &str)],
+    live: &[(u64, &str)],
+    same_image: impl Fn(usize, usize) -> bool,
+) -> Result<Vec<Option<usize>>, String> {
+    let mut pairs = vec![None; wanted.len()];
+    let mut claimed = vec![false; live.len()];
+    // Pinned ids first, so a name can't take them
+    for (slot, (id, name)) in pairs.iter_mut().zip(wanted) {
+        if let Some(id) = id {
+            let j = live
+                .iter()
+                .position(|(l, _)| l == id)
+                .ok_or_else(|| format!("{name}: the guild has nothing with the id {id}"))?;
+            if claimed[j] {
+                return Err(format!("{name}: the id {id} is pinned twice"));
+            }
+            claimed[j] = true;
+            *slot = Some(j);
+        }
+    }
+    for (slot, (_, name)) in pairs.iter_mut().zip(wanted) {
+        if slot.is_none() {
+            if let Some(j) = (0..live.len()).find(|&j| !claimed[j] && live[j].1 == *name) {
+                claimed[j] = true;
+                *slot = Some(j);
+            }
+        }
+    }
+    for (i, slot) in pairs.iter_mut().enumerate() {
+        if slot.is_none() {
+            if let Some(j) = (0..live.len()).find(|&j| !claimed[j] && same_image(i, j)) {
+                claimed[j] = true;
+                *slot = Some(j);
+            }
+        }
+    }
+    Ok(pairs)
+}
+
+/// Turn the pairs into steps, items of the guild missing from the manifest are deleted
+/// when `prune` is set
+fn plan(
+    kind: SyncKind,
+    pairs: &[Option<usize>],
+    wanted: &[(Option<u64>, &str)],
+    live: &[(u64, &str)],
+    prune: bool,
+    same_image: impl Fn(usize, usize) -> bool,
+    same_metadata: impl Fn(usize, usize) -> bool,
+) -> Vec<Step> {
+    let step =
+        |action, name: &str, previous_name: Option<&str>, id, wanted_index, live_index| Step {
+            item: SyncItem {
+                kind,
+                action,
+                name: name.to_string(),
+                previous_name: previous_name.map(ToString::to_string),
+                id,
+                error: None,
+            },
+            wanted: wanted_index,
+            live: live_index,
+        };
+    let mut steps = Vec::with_capacity(wanted.len());
+    for (i, (&(_, name), pair)) in wanted.iter().zip(pairs).enumerate() {
+        steps.push(match *pair {
+            None => step(SyncAction::Create, name, None, None, Some(i), None),
+            Some(j) => {
+                let (id, live_name) = live[j];
+                let action = if !same_image(i, j) {
+                    SyncAction::Replace
+                } else if live_name != name {
+                    SyncAction::Rename
+                } else if !same_metadata(i, j) {
+                    SyncAction::Update
+                } else {
+                    SyncAction::Keep
+                };
+                let previous_name = (live_name != name).then_some(live_name);
+                step(action, name, previous_name, Some(id), Some(i), Some(j))
+            }
+        });
+    }
+    if prune {
+        for (j, &(id, name)) in live.iter().enumerate() {
+            if !pairs.contains(&Some(j)) {
+                steps.push(step(
+                    SyncAction::Delete,
+                    name,
+                    None,
+                    Some(id),
+                    None,
+                    Some(j),
+                ));
+            }
+        }
+    }
+    steps
+}
+
+async fn emoji_steps(
+    logic: &crate::discord::Logic,
+    guildid: u64,
+    wanted: &[WantedEmoji],
+    prune: bool,
+) -> Result<(Vec<Step>, Vec<EmojiItem>), String> {
+    // Emojis of integrations can't be managed
+    let (live, live_hashes): (Vec<_>, Vec<_>) = crate::dedup::live_emoji_hashes(logic, guildid)
+        .await
+        .into_iter()
+        .filter(|(e, _)| !e.managed)
+        .unzip();
+    let wanted_keys = wanted
+        .iter()
+        .map(|w| (w.id, w.name.as_str()))
+        .collect::<Vec<_>>();
+    let live_keys = live
+        .iter()
+        .map(|l| (l.id, l.name.as_str()))
+        .collect::<Vec<_>>();
+    let similar = |i: usize, j: usize| {
+        crate::dedup::similarity(&wanted[i].hashes, &live_hashes[j]) >= SAME_IMAGE
+    };
+    let pairs = pair(&wanted_keys, &live_keys, similar)?;
+    let steps = plan(
+        SyncKind::Emoji,
+        &pairs,
+        &wanted_keys,
+        &live_keys,
+        prune,
+        // Without hashes we can't tell, the emoji is kept rather than replaced
+        |i, j| wanted[i].hashes.is_empty() || live_hashes[j].is_empty() || similar(i, j),
+        |i, j| {
+            wanted[i].roles.iter().collect::<HashSet<_>>()
+                == live[j].roles.iter().collect::<HashSet<_>>()
+        },
+    );
+    Ok((steps, live))
+}
+
+async fn sticker_steps(
+    logic: &crate::discord::Logic,
+    guildid: u64,
+    wanted: &[WantedSticker],
+    prune: bool,
+) -> Result<(Vec<Step>, Vec<StickerItem>), String> {
+    let live = logic
+        .get_guild(guildid)
+        .map(|g| g.stickers.clone())
+        .unwrap_or_default();
+    let live_images = futures::future::join_all(live.iter().map(|s| async move {
+        match logic.fetch_asset(&s.cdn_url()).await {
+            Ok(bytes) => tokio::task::spawn_blocking(move || StickerImage::new(&bytes))
+                .await
+                .ok()
+                .flatten(),
+            Err(e) => {
+                warn!("Error when fetching sticker {}: {e}", s.id);
+                None
+            }
+        }
+    }))
+    .await;
+    let wanted_keys = wanted
+        .iter()
+        .map(|w| (w.id, w.name.as_str()))
+        .collect::<Vec<_>>();
+    let live_keys = live
+        .iter()
+        .map(|l| (l.id, l.name.as_str()))
+        .collect::<Vec<_>>();
+    let similar = |i: usize, j: usize| match (&wanted[i].image, &live_images[j]) {
+        (Some(a), Some(b)) => a.same(b),
+        _ => false,
+    };
+    let pairs = pair(&wanted_keys, &live_keys, similar)?;
+    let steps = plan(
+        SyncKind::Sticker,
+        &pairs,
+        &wanted_keys,
+        &live_keys,
+        prune,
+        // Without the image we can't tell, the sticker is kept rather than replaced
+        |i, j| wanted[i].image.is_none() || live_images[j].is_none() || similar(i, j),
+        |i, j| {
+            wanted[i].description.as_deref().unwrap_or_default()
+                == live[j].description.as_deref().unwrap_or_default()
+                && Some(wanted[i].tags.as_str()) == live[j].tags.as_deref()
+        },
+    );
+    Ok((steps, live))
+}
+
+type ApplyError = Box<dyn std::error::Error + Send + Sync>;
+
+async fn delete_emoji(
+    logic: &crate::discord::Logic,
+    guildid: u64,
+    emoji_id: u64,
+) -> Result<(), ApplyError> {
+    logic.delete_guild_emoji(guildid, emoji_id).await?;
+    if let Some(mut guild) = logic.guilds.get_mut(&guildid) {
+        guild.emojis.retain(|e| e.id != emoji_id);
+    }
+    Ok(())
+}
+
+async fn create_emoji(
+    logic: &crate::discord::Logic,
+    guildid: u64,
+    wanted: &WantedEmoji,
+) -> Result<u64, ApplyError> {
+    let mut new = logic
+        .create_guild_emoji(
+            guildid,
+            &wanted.name,
+            &wanted.image.image_type.to_content_type(),
+            &wanted.image.data,
+        )
+        .await?;
+    if !wanted.roles.is_empty() {
+        new = logic
+            .modify_guild_emoji(guildid, new.id, None, Some(wanted.roles.as_slice()))
+            .await?;
+    }
+    let new_id = new.id;
+    logic.emoji_hashes.insert(new_id, wanted.hashes.clone());
+    if let Some(mut guild) = logic.guilds.get_mut(&guildid) {
+        guild.emojis.push(new);
+    }
+    Ok(new_id)
+}
+
+/// Run an emoji step through Discord, returns the id of the created emoji
+async fn apply_emoji(
+    logic: &crate::discord::Logic,
+    guildid: u64,
+    step: &Step,
+    wanted: &[WantedEmoji],
+    live: &[EmojiItem],
+) -> Result<Option<u64>, ApplyError> {
+    let wanted = step.wanted.map(|i| &wanted[i]);
+    let live = step.live.map(|j| &live[j]);
+    match (step.item.action, wanted, live) {
+        (SyncAction::Delete, _, Some(live)) => {
+            delete_emoji(logic, guildid, live.id).await?;
+            Ok(None)
+        }
+        (SyncAction::Rename | SyncAction::Update, Some(wanted), Some(live)) => {
+            let name = (live.name != wanted.name).then_some(wanted.name.as_str());
+            let updated = logic
+                .modify_guild_emoji(guildid, live.id, name, Some(wanted.roles.as_slice()))
+                .await?;
+            if let Some(mut guild) = logic.guilds.get_mut(&guildid) {
+                if let Some(e) = guild.emojis.iter_mut().find(|e| e.id == live.id) {
+                    *e = updated;
+                }
+            }
+            Ok(None)
+        }
+        (SyncAction::Create, Some(wanted), _) => {
+            create_emoji(logic, guildid, wanted).await.map(Some)
+        }
+        (SyncAction::Replace, Some(wanted), Some(live)) => {
+            // The new emoji is created first so a failure leaves the old one in place,
+            // unless the guild has no slot left for it
+            let animated = matches!(wanted.image.image_type, crate::image::ImageType::Gif);
+            let full = logic.get_guild(guildid).map_or(false, |g| {
+                let capacity = g.capacity();
+                let slots = if animated {
+                    capacity.animated_emojis
+                } else {
+                    capacity.static_emojis
+                };
+                slots.available == 0
+            });
+            if full {
+                delete_emoji(logic, guildid, live.id).await?;
+            }
+            let new_id = create_emoji(logic, guildid, wanted).await?;
+            if !full {
+                delete_emoji(logic, guildid, live.id).await.map_err(|e| {
+                    format!("Created as {new_id}, but the previous emoji wasn't deleted: {e}")
+                })?;
+            }
+            Ok(Some(new_id))
+        }
+        _ => Ok(None),
+    }
+}
+
+async fn delete_sticker(
+    logic: &crate::discord::Logic,
+    guildid: u64,
+    sticker_id: u64,
+) -> Result<(), ApplyError> {
+    logic.delete_guild_sticker(guildid, sticker_id).await?;
+    if let Some(mut guild) = logic.guilds.get_mut(&guildid) {
+        guild.stickers.retain(|s| s.id != sticker_id);
+    }
+    Ok(())
+}
+
+async fn create_sticker(
+    logic: &crate::discord::Logic,
+    guildid: u64,
+    wanted: &WantedSticker,
+) -> Result<u64, ApplyError> {
+    let new = logic
+        .create_guild_sticker(
+            guildid,
+            &wanted.name,
+            wanted.description.as_deref().unwrap_or_default(),
+            &wanted.tags,
+            &wanted.image_type.file_name(&wanted.name),
+            &wanted.image_type.to_content_type(),
+            wanted.data.clone(),
+        )
+        .await?;
+    let new_id = new.id;
+    if let Some(mut guild) = logic.guilds.get_mut(&guildid) {
+        guild.stickers.push(new);
+    }
+    Ok(new_id)
+}
+
+/// Run a sticker step through Discord, returns the id of the created sticker
+async fn apply_sticker(
+    logic: &crate::discord::Logic,
+    guildid: u64,
+    step: &Step,
+    wanted: &[WantedSticker],
+    live: &[StickerItem],
+) -> Result<Option<u64>, ApplyError> {
+    let wanted = step.wanted.map(|i| &wanted[i]);
+    let live = step.live.map(|j| &live[j]);
+    match (step.item.action, wanted, live) {
+        (SyncAction::Delete, _, Some(live)) => {
+            delete_sticker(logic, guildid, live.id).await?;
+            Ok(None)
+        }
+        (SyncAction::Rename | SyncAction::Update, Some(wanted), Some(live)) => {
+            let name = (live.name != wanted.name).then_some(wanted.name.as_str());
+            let updated = logic
+                .modify_guild_sticker(
+                    guildid,
+                    live.id,
+                    name,
+                    Some(wanted.description.as_deref().unwrap_or_default()),
+                    Some(wanted.tags.as_str()),
+                )
+                .await?;
+            if let Some(mut guild) = logic.guilds.get_mut(&guildid) {
+                if let Some(s) = guild.stickers.iter_mut().find(|s| s.id == live.id) {
+                    *s = updated;
+                }
+            }
+            Ok(None)
+        }
+        (SyncAction::Create, Some(wanted), _) => {
+            create_sticker(logic, guildid, wanted).await.map(Some)
+        }
+        (SyncAction::Replace, Some(wanted), Some(live)) => {
+            // Same as emojis, the old sticker only goes first when there is no slot left
+            let full = logic
+                .get_guild(guildid)
+                .map_or(false, |g| g.capacity().stickers.available == 0);
+            if full {
+                delete_sticker(logic, guildid, live.id).await?;
+            }
+            let new_id = create_sticker(logic, guildid, wanted).await?;
+            if !full {
+                delete_sticker(logic, guildid, live.id).await.map_err(|e| {
+                    format!("Created as {new_id}, but the previous sticker wasn't deleted: {e}")
+                })?;
+            }
+            Ok(Some(new_id))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Compare the emojis and stickers of a guild with a manifest, and make the guild
+/// match it when `apply` is set. Entries are matched with the guild's items by pinned id,
+/// then by name, then by image.
+#[openapi]
+#[post("/guild/<id>/sync?<apply>", data = "<request>")]
+pub async fn sync_guild(
+    user: crate::auth::User,
+    logic: &rocket::State<crate::discord::Logic>,
+    id: u64,
+    apply: Option<bool>,
+    request: rocket::serde::json::Json<SyncRequest>,
+) -> Rsp<SyncResult> {
+    if !logic
+        .user_has_permission(
+            &user.session,
+            id,
+            crate::discord::MANAGE_EMOJIS_AND_STICKERS,
+        )
+        .await
+    {
+        return Rsp::err(
+            Error::Unauthorized,
+            "You are not in the guild or don't have permission to do so"
+                .to_string()
+                .into(),
+        );
+    }
+    if logic.get_guild(id).is_none() {
+        return Rsp::err(Error::InvalidRequest, "Unknown guild".to_string().into());
+    }
+    let SyncRequest {
+        emojis,
+        stickers,
+        prune,
+    } = request.into_inner();
+    let emojis = match prepare_emojis(emojis).await {
+        Ok(e) => e,
+        Err(e) => return Rsp::err(Error::InvalidRequest, Some(e)),
+    };
+    let stickers = match prepare_stickers(stickers) {
+        Ok(s) => s,
+        Err(e) => return Rsp::err(Error::InvalidRequest, Some(e)),
+    };
+    let (mut emoji_plan, live_emojis) = match emoji_steps(logic, id, &emojis, prune).await {
+        Ok(s) => s,
+        Err(e) => return Rsp::err(Error::InvalidRequest, Some(e)),
+    };
+    let (mut sticker_plan, live_stickers) = match sticker_steps(logic, id, &stickers, prune).await {
+        Ok(s) => s,
+        Err(e) => return Rsp::err(Error::InvalidRequest, Some(e)),
+    };
+
+    let apply = apply.unwrap_or(false);
+    if apply {
+        for action in APPLY_ORDER {
+            for step in emoji_plan.iter_mut().filter(|s| s.item.action == action) {
+                match apply_emoji(logic, id, step, &emojis, &live_emojis).await {
+                    Ok(Some(new_id)) => step.item.id = Some(new_id),
+                    Ok(None) => {}
+                    Err(e) => step.item.error = Some(format!("{e}")),
+                }
+            }
+            for step in sticker_plan.iter_mut().filter(|s| s.item.action == action) {
+                match apply_sticker(logic, id, step, &stickers, &live_stickers).await {
+                    Ok(Some(new_id)) => step.item.id = Some(new_id),
+                    Ok(None) => {}
+                    Err(e) => step.item.error = Some(format!("{e}")),
+                }
+            }
+        }
+    }
+    Rsp::ok(SyncResult {
+        applied: apply,
+        items: emoji_plan
+            .into_iter()
+            .chain(sticker_plan)
+            .map(|s| s.item)
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIVE: [(u64, &str); 3] = [(10, "party"), (11, "wave"), (12, "cat")];
+
+    fn no_image(_: usize, _: usize) -> bool {
+        false
+    }
+
+    fn actions(steps: &[Step]) -> Vec<(SyncAction, &str, Option<u64>)> {
+        steps
+            .iter()
+            .map(|s| (s.item.action, s.item.name.as_str(), s.item.id))
+            .collect()
+    }
+
+    #[test]
+    fn pinned_ids_win_over_names() {
+        // "wave" is pinned to the live "party", which the entry named "party" can't take
+        let wanted = [(None, "party"), (Some(10), "wave")];
+        let pairs = pair(&wanted, &LIVE, no_image).unwrap();
+        assert_eq!(pairs, vec![None, Some(0)]);
+    }
+
+    #[test]
+    fn unknown_or_repeated_pinned_ids_fail() {
+        assert!(pair(&[(Some(99), "party")], &LIVE, no_image).is_err());
+        assert!(pair(&[(Some(10), "party"), (Some(10), "wave")], &LIVE, no_image).is_err());
+    }
+
+    #[test]
+    fn names_then_images() {
+        let wanted = [(None, "cat"), (None, "hello"), (None, "bye")];
+        // "hello" has the image of the live "wave"
+        let pairs = pair(&wanted, &LIVE, |i, j| (i, j) == (1, 1)).unwrap();
+        assert_eq!(pairs, vec![Some(2), Some(1), None]);
+    }
+
+    #[test]
+    fn an_item_is_paired_once() {
+        // Both entries have the image of "party", only the first gets it
+        let wanted = [(None, "a"), (None, "b")];
+        let pairs = pair(&wanted, &LIVE, |_, j| j == 0).unwrap();
+        assert_eq!(pairs, vec![Some(0), None]);
+    }
+
+    #[test]
+    fn plan_actions() {
+        let wanted = [
+            (None, "party"),
+            (None, "hello"),
+            (None, "cat"),
+            (None, "new"),
+        ];
+        let pairs = [Some(0), Some(1), Some(2), None];
+        // "party" changed its image, "cat" its roles
+        let steps = plan(
+            SyncKind::Emoji,
+            &pairs,
+            &wanted,
+            &LIVE,
+            false,
+            |i, _| i != 0,
+            |i, _| i != 2,
+        );
+        assert_eq!(
+            actions(&steps),
+            vec![
+                (SyncAction::Replace, "party", Some(10)),
+                (SyncAction::Rename, "hello", Some(11)),
+                (SyncAction::Update, "cat", Some(12)),
+                (SyncAction::Create, "new", None),
+            ]
+        );
+        assert_eq!(steps[1].item.previous_name.as_deref(), Some("wave"));
+        assert_eq!(steps[0].item.previous_name, None);
+    }
+
+    #[test]
+    fn unchanged_items_are_kept() {
+        let wanted = [(None, "party")];
+        let steps = plan(
+            SyncKind::Sticker,
+            &[Some(0)],
+            &wanted,
+            &LIVE,
+            false,
+            |_, _| true,
+            |_, _| true,
+        );
+        assert_eq!(actions(&steps), vec![(SyncAction::Keep, "party", Some(10))]);
+        assert_eq!(steps[0].item.kind, SyncKind::Sticker);
+    }
+
+    #[test]
+    fn prune_deletes_unlisted_items() {
+        let wanted = [(None, "wave")];
+        let pairs = [Some(1)];
+        let same = |_: usize, _: usize| true;
+        let kept = plan(SyncKind::Emoji, &pairs, &wanted, &LIVE, false, same, same);
+        assert_eq!(actions(&kept), vec![(SyncAction::Keep, "wave", Some(11))]);
+        let pruned = plan(SyncKind::Emoji, &pairs, &wanted, &LIVE, true, same, same);
+        assert_eq!(
+            actions(&pruned),
+            vec![
+                (SyncAction::Keep, "wave", Some(11)),
+                (SyncAction::Delete, "party", Some(10)),
+                (SyncAction::Delete, "cat", Some(12)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::json;
+
+const GOOGLE_SAFESAERCH_URL: &str = "https://vision.googleapis.com/v1/images:annotate";
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Something able to rate an uploaded image
+#[async_trait]
+pub trait ModerationProvider: Send + Sync {
+    /// Rate the upload persisted at `path`
+    async fn rate(&self, path: &std::path::Path) -> Result<ImageRating, BoxError>;
+}
+
+/// Google Vision SafeSearch, the image is sent inline
+pub struct SafeSearch {
+    client: reqwest::Client,
+    google_token: String,
+}
+
+/// Google Vision SafeSearch, Google fetches the image from `external_url`
+pub struct SafeSearchRemote {
+    client: reqwest::Client,
+    google_token: String,
+    external_url: String,
+}
+
+/// Accept every upload, for instances without any moderation service
+pub struct Disabled;
+
+/// POST the image to a local HTTP service that answers with an `ImageRating`
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SafeSearchResponse {
+    responses: Vec<SafeSearchAnnotation>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SafeSearchAnnotation {
+    safe_search_annotation: Option<ImageRating>,
+    error: Option<serde_json::Value>,
+}
+
+async fn safe_search(
+    client: &reqwest::Client,
+    google_token: &str,
+    image: serde_json::Value,
+) -> Result<ImageRating, BoxError> {
+    let response = client
+        .post(GOOGLE_SAFESAERCH_URL)
+        .bearer_auth(google_token)
+        .json(&json! {
+            {
+              "requests": [
+                {
+                  "image": image,
+                  "features": [
+                    {
+                      "type": "SAFE_SEARCH_DETECTION"
+                    }
+                  ]
+                }
+              ]
+            }
+        })
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<SafeSearchResponse>()
+        .await?;
+    match response.responses.into_iter().next() {
+        Some(SafeSearchAnnotation {
+            safe_search_annotation: Some(rating),
+            ..
+        }) => Ok(rating),
+        Some(SafeSearchAnnotation { error: Some(e), .. }) => {
+            Err(format!("SafeSearch returned an error: {e}").into())
+        }
+        _ => Err("SafeSearch didn't rate the image".into()),
+    }
+}
+
+#[async_trait]
+impl ModerationProvider for SafeSearch {
+    async fn rate(&self, path: &std::path::Path) -> Result<ImageRating, BoxError> {
+        let encoded = base64::encode(tokio::fs::read(path).await?);
+        safe_search(
+            &self.client,
+            &self.google_token,
+            json!({ "content": encoded }),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl ModerationProvider for SafeSearchRemote {
+    async fn rate(&self, path: &std::path::Path) -> Result<ImageRating, BoxError> {
+        let img = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or("Invalid upload path")?;
+        safe_search(
+            &self.client,
+            &self.google_token,
+            json!({ "source": { "imageUri": format!("{}/tmp/{img}", self.external_url) } }),
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl ModerationProvider for Disabled {
+    async fn rate(&self, _path: &std::path::Path) -> Result<ImageRating, BoxError> {
+        Ok(ImageRating::UNRATED)
+    }
+}
+
+#[async_trait]
+impl ModerationProvider for Webhook {
+    async fn rate(&self, path: &std::path::Path) -> Result<ImageRating, BoxError> {
+        self.client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(tokio::fs::read(path).await?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<ImageRating>()
+            .await
+            .map_err(Into::into)
+    }
+}
+
+/// The most likely rating accepted for each category
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Thresholds {
+    pub adult: Rating,
+    pub racy: Rating,
+    pub spoof: Rating,
+    #[serde(alias = "violence")]
+    pub violance: Rating,
+    pub medical: Rating,
+}
+
+impl Thresholds {
+    /// `Rating` goes from `VeryLikely` (lowest) to `Unknown` (highest).
+    /// Ratings at most `quarantine_margin` steps under a threshold are left to a moderator.
+    pub fn verdict(&self, rating: &ImageRating, quarantine_margin: u8) -> Verdict {
+        let deficit = [
+            (self.adult, rating.adult),
+            (self.racy, rating.racy),
+            (self.spoof, rating.spoof),
+            (self.violance, rating.violance),
+            (self.medical, rating.medical),
+        ]
+        .into_iter()
+        .map(|(threshold, rating)| threshold as i32 - rating as i32)
+        .max()
+        .unwrap_or_default();
+        if deficit <= 0 {
+            Verdict::Accept
+        } else if deficit <= quarantine_margin as i32 {
+            Verdict::Quarantine
+        } else {
+            Verdict::Reject
+        }
+    }
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            adult: Rating::Possible,
+            racy: Rating::Possible,
+            spoof: Rating::VeryLikely,
+            violance: Rating::Possible,
+            medical: Rating::Possible,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Quarantine,
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderKind {
+    Safesearch,
+    SafesearchRemote,
+    Disabled,
+    Webhook,
+}
+
+pub struct Moderation {
+    provider: Box<dyn ModerationProvider>,
+    thresholds: Thresholds,
+    guild_thresholds: HashMap<u64, Thresholds>,
+    quarantine_margin: u8,
+}
+
+impl Moderation {
+    /// Read the `dem.moderation` section.
+    /// Without one, SafeSearch is used with `dem.google_token` like before.
+    pub fn from_figment(
+        figment: &rocket::figment::Figment,
+    ) -> Result<Self, rocket::figment::Error> {
+        #[derive(Deserialize)]
+        struct Config {
+            provider: ProviderKind,
+            google_token: Option<String>,
+            external_url: Option<String>,
+            webhook_url: Option<String>,
+            #[serde(default)]
+            thresholds: Thresholds,
+            /// Thresholds overriden for some guilds, keyed by guild id
+            #[serde(default)]
+            guilds: HashMap<String, Thresholds>,
+            /// How many steps under a threshold still goes to the quarantine instead of being rejected
+            #[serde(default = "default_quarantine_margin")]
+            quarantine_margin: u8,
+        }
+        fn default_quarantine_margin() -> u8 {
+            1
+        }
+
+        let config = match figment.find_value("dem.moderation") {
+            Ok(_) => figment.extract_inner::<Config>("dem.moderation")?,
+            Err(_) => Config {
+                provider: ProviderKind::Safesearch,
+                google_token: None,
+                external_url: None,
+                webhook_url: None,
+                thresholds: Thresholds::default(),
+                guilds: HashMap::new(),
+                quarantine_margin: default_quarantine_margin(),
+            },
+        };
+        let google_token = || {
+            config
+                .google_token
+                .clone()
+                .map(Ok)
+                .unwrap_or_else(|| figment.extract_inner::<String>("dem.google_token"))
+        };
+        let missing = |field: &str| {
+            rocket::figment::Error::from(format!(
+                "dem.moderation.{field} is needed by the {:?} moderation provider",
+                config.provider
+            ))
+        };
+
+        let client = reqwest::Client::new();
+        let provider: Box<dyn ModerationProvider> = match config.provider {
+            ProviderKind::Safesearch => Box::new(SafeSearch {
+                client,
+                google_token: google_token()?,
+            }),
+            ProviderKind::SafesearchRemote => Box::new(SafeSearchRemote {
+                client,
+                google_token: google_token()?,
+                external_url: config
+                    .external_url
+                    .clone()
+                    .ok_or_else(|| missing("external_url"))?,
+            }),
+            ProviderKind::Disabled => {
+                warn!("Content moderation is disabled, every upload will be accepted");
+                Box::new(Disabled)
+            }
+            ProviderKind::Webhook => Box::new(Webhook {
+                client,
+                url: config
+                    .webhook_url
+                    .clone()
+                    .ok_or_else(|| missing("webhook_url"))?,
+            }),
+        };
+
+        let guild_thresholds = config
+            .guilds
+            .iter()
+            .map(|(guildid, thresholds)| {
+                guildid
+                    .parse::<u64>()
+                    .map(|id| (id, *thresholds))
+                    .map_err(|_| {
+                        rocket::figment::Error::from(format!(
+                            "dem.moderation.guilds: {guildid} isn't a guild id"
+                        ))
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Moderation {
+            provider,
+            thresholds: config.thresholds,
+            guild_thresholds,
+            quarantine_margin: config.quarantine_margin,
+        })
+    }
+
+    pub async fn rate(&self, path: &std::path::Path) -> Result<ImageRating, BoxError> {
+        self.provider.rate(path).await
+    }
+
+    pub fn thresholds(&self, guildid: u64) -> &Thresholds {
+        self.guild_thresholds
+            .get(&guildid)
+            .unwrap_or(&self.thresholds)
+    }
+
+    pub fn verdict(&self, guildid: u64, rating: &ImageRating) -> Verdict {
+        self.thresholds(guildid)
+            .verdict(rating, self.quarantine_margin)
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    serde::Deserialize,
+    serde::Serialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    schemars::JsonSchema,
+)]
+pub struct ImageRating {
+    pub racy: Rating,
+    pub adult: Rating,
+    pub spoof: Rating,
+    #[serde(alias = "violence")]
+    pub violance: Rating,
+    pub medical: Rating,
+}
+
+impl ImageRating {
+    /// What a provider that doesn't look at images answers
+    pub const UNRATED: ImageRating = ImageRating {
+        racy: Rating::Unknown,
+        adult: Rating::Unknown,
+        spoof: Rating::Unknown,
+        violance: Rating::Unknown,
+        medical: Rating::Unknown,
+    };
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    serde::Deserialize,
+    serde::Serialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    schemars::JsonSchema,
+)]
+pub enum Rating {
+    #[serde(rename = "UNKNOWN")]
+    Unknown = 5,
+    #[serde(rename = "VERY_UNLIKELY")]
+    VeryUnlikely = 4,
+    #[serde(rename = "UNLIKELY")]
+    Unlikely = 3,
+    #[serde(rename = "POSSIBLE")]
+    Possible = 2,
+    #[serde(rename = "LIKELY")]
+    Likely = 1,
+    #[serde(rename = "VERY_LIKELY")]
+    VeryLikely = 0,
+}
@@ -0,0 +1,249 @@
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::{CompressionType, FilterType as PngFilter, PngEncoder},
+    },
+    imageops::FilterType,
+    AnimationDecoder, ColorType, Delay, Frame, ImageDecoder, ImageEncoder, ImageFormat, RgbaImage,
+};
+
+use crate::image::ImageType;
+
+pub const EMOJI_MAX_SIZE: usize = 256 * 1024;
+pub const EMOJI_DIMENSIONS: u32 = 128;
+/// How many times we try to shrink an animated emoji before giving up
+const MAX_GIF_PASSES: u32 = 8;
+/// Images wider or taller than this are refused before being decoded
+pub const MAX_DECODED_DIMENSIONS: u32 = 4096;
+/// Memory a decoded image, or every frame of an animation together, may take
+pub const MAX_DECODED_BYTES: u64 = 256 * 1024 * 1024;
+/// Animations with more frames than this are refused
+pub const MAX_FRAMES: usize = 1000;
+
+pub struct Processed {
+    pub data: Vec<u8>,
+    pub image_type: ImageType,
+}
+
+#[derive(Debug)]
+pub enum ProcessingError {
+    Unsupported,
+    Image(image::ImageError),
+    TooBig,
+    /// The image would take too much memory once decoded
+    TooLarge,
+}
+
+impl std::fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "Unsupported image format"),
+            Self::Image(e) => write!(f, "Invalid image: {e}"),
+            Self::TooBig => write!(f, "Couldn't fit the image under 256 KiB"),
+            Self::TooLarge => write!(f, "The image is too large to be processed"),
+        }
+    }
+}
+
+impl std::error::Error for ProcessingError {}
+
+impl From<image::ImageError> for ProcessingError {
+    fn from(e: image::ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+/// Turn an uploaded PNG, GIF, JPEG or WebP into an emoji Discord accepts:
+/// at most 128x128 and 256 KiB, as a PNG or an animated GIF
+pub fn normalize_emoji(bytes: &[u8]) -> Result<Processed, ProcessingError> {
+    match image::guess_format(bytes).map_err(|_| ProcessingError::Unsupported)? {
+        ImageFormat::Gif => {
+            let frames = decode_gif(bytes)?;
+            if frames.len() > 1 {
+                return normalize_animated(frames);
+            }
+            let image = frames
+                .into_iter()
+                .next()
+                .ok_or(ProcessingError::Unsupported)?
+                .into_buffer();
+            normalize_static(&image)
+        }
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP => {
+            normalize_static(&decode_static(bytes)?)
+        }
+        _ => Err(ProcessingError::Unsupported),
+    }
+}
+
+fn decode_limits() -> image::io::Limits {
+    let mut limits = image::io::Limits::default();
+    limits.max_image_width = Some(MAX_DECODED_DIMENSIONS);
+    limits.max_image_height = Some(MAX_DECODED_DIMENSIONS);
+    limits.max_alloc = Some(MAX_DECODED_BYTES);
+    limits
+}
+
+/// Decode a PNG, JPEG or WebP, refusing images above the decoding limits
+pub fn decode_static(bytes: &[u8]) -> Result<RgbaImage, ProcessingError> {
+    let mut reader = image::io::Reader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(image::ImageError::from)?;
+    reader.limits(decode_limits());
+    match reader.decode() {
+        Ok(image) => Ok(image.into_rgba8()),
+        Err(image::ImageError::Limits(_)) => Err(ProcessingError::TooLarge),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Decode the frames of a GIF one at a time, giving up as soon as they'd take more
+/// than `MAX_DECODED_BYTES` or `MAX_FRAMES`
+pub fn decode_gif(bytes: &[u8]) -> Result<Vec<Frame>, ProcessingError> {
+    let decoder = GifDecoder::new(bytes)?;
+    let (width, height) = decoder.dimensions();
+    if width > MAX_DECODED_DIMENSIONS || height > MAX_DECODED_DIMENSIONS {
+        return Err(ProcessingError::TooLarge);
+    }
+    // Every frame is decoded into a canvas as big as the GIF
+    let frame_bytes = (width as u64 * height as u64 * 4).max(1);
+    let max_frames = MAX_FRAMES.min((MAX_DECODED_BYTES / frame_bytes) as usize);
+    let mut frames = Vec::new();
+    for frame in decoder.into_frames() {
+        if frames.len() == max_frames {
+            return Err(ProcessingError::TooLarge);
+        }
+        frames.push(frame?);
+    }
+    Ok(frames)
+}
+
+fn normalize_static(image: &RgbaImage) -> Result<Processed, ProcessingError> {
+    let image = fit(image);
+    for dropped_bits in 0..4 {
+        let data = encode_png(&posterize(&image, dropped_bits))?;
+        if data.len() <= EMOJI_MAX_SIZE {
+            return Ok(Processed {
+                data,
+                image_type: ImageType::Png,
+            });
+        }
+    }
+    Err(ProcessingError::TooBig)
+}
+
+/// Re-encode the animation, alternating between dropping frames
+/// and reducing colours until it fits
+fn normalize_animated(frames: Vec<Frame>) -> Result<Processed, ProcessingError> {
+    let mut frames: Vec<(RgbaImage, Delay)> = frames
+        .into_iter()
+        .map(|f| {
+            let delay = f.delay();
+            (fit(&f.into_buffer()), delay)
+        })
+        .collect();
+    let mut dropped_bits = 0;
+    for pass in 0..MAX_GIF_PASSES {
+        let data = encode_gif(&frames, dropped_bits)?;
+        if data.len() <= EMOJI_MAX_SIZE {
+            return Ok(Processed {
+                data,
+                image_type: ImageType::Gif,
+            });
+        }
+        debug!(
+            "Animated emoji is still {} bytes after {pass} passes",
+            data.len()
+        );
+        if pass % 2 == 0 && frames.len() > 1 {
+            frames = drop_frames(frames);
+        } else if dropped_bits < 5 {
+            dropped_bits += 1;
+        }
+    }
+    Err(ProcessingError::TooBig)
+}
+
+/// Downscale into a square canvas of at most `EMOJI_DIMENSIONS`, keeping the aspect ratio
+/// and padding with transparency
+fn fit(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let longest = width.max(height).max(1);
+    let side = longest.min(EMOJI_DIMENSIONS);
+    let scale = side as f64 / longest as f64;
+    let new_width = ((width as f64 * scale).round() as u32).max(1);
+    let new_height = ((height as f64 * scale).round() as u32).max(1);
+    let resized = if (new_width, new_height) == (width, height) {
+        image.clone()
+    } else {
+        image::imageops::resize(image, new_width, new_height, FilterType::Lanczos3)
+    };
+    let mut canvas = RgbaImage::new(side, side);
+    image::imageops::overlay(
+        &mut canvas,
+        &resized,
+        ((side - new_width) / 2) as i64,
+        ((side - new_height) / 2) as i64,
+    );
+    canvas
+}
+
+/// Clear the lowest bits of every colour channel, which leaves fewer colours to compress
+fn posterize(image: &RgbaImage, dropped_bits: u8) -> RgbaImage {
+    let mut image = image.clone();
+    if dropped_bits > 0 {
+        let mask = 0xffu8 << dropped_bits;
+        for pixel in image.pixels_mut() {
+            for channel in &mut pixel.0[..3] {
+                *channel &= mask;
+            }
+        }
+    }
+    image
+}
+
+/// Keep every other frame, each one shown for as long as the frame it replaces
+fn drop_frames(frames: Vec<(RgbaImage, Delay)>) -> Vec<(RgbaImage, Delay)> {
+    let mut kept = Vec::with_capacity(frames.len() / 2 + 1);
+    let mut frames = frames.into_iter();
+    while let Some((image, delay)) = frames.next() {
+        let delay = match frames.next() {
+            Some((_, skipped)) => add_delays(delay, skipped),
+            None => delay,
+        };
+        kept.push((image, delay));
+    }
+    kept
+}
+
+fn add_delays(a: Delay, b: Delay) -> Delay {
+    let ms = |d: Delay| {
+        let (numerator, denominator) = d.numer_denom_ms();
+        numerator as f64 / denominator.max(1) as f64
+    };
+    Delay::from_numer_denom_ms((ms(a) + ms(b)).round() as u32, 1)
+}
+
+fn encode_png(image: &RgbaImage) -> Result<Vec<u8>, ProcessingError> {
+    let mut data = Vec::new();
+    PngEncoder::new_with_quality(&mut data, CompressionType::Best, PngFilter::Adaptive)
+        .write_image(
+            image.as_raw(),
+            image.width(),
+            image.height(),
+            ColorType::Rgba8,
+        )?;
+    Ok(data)
+}
+
+fn encode_gif(frames: &[(RgbaImage, Delay)], dropped_bits: u8) -> Result<Vec<u8>, ProcessingError> {
+    let mut data = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut data, 10);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames.iter().map(|(image, delay)| {
+            Frame::from_parts(posterize(image, dropped_bits), 0, 0, *delay)
+        }))?;
+    }
+    Ok(data)
+}
@@ -0,0 +1,85 @@
+use dem_types::api::QuotaUsage;
+use serde::Deserialize;
+
+/// Limits on what can be stored, read from the `dem.quota` section.
+/// A missing limit means there is none.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Quotas {
+    pub guild_items: Option<u64>,
+    pub guild_bytes: Option<u64>,
+    /// Per user, in each guild
+    pub user_items: Option<u64>,
+    pub user_bytes: Option<u64>,
+    /// Uploads a user can do in a guild every `window_secs`
+    pub uploads_per_window: Option<u64>,
+    pub window_secs: u64,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Quotas {
+            guild_items: None,
+            guild_bytes: None,
+            user_items: None,
+            user_bytes: None,
+            uploads_per_window: None,
+            window_secs: 3600,
+        }
+    }
+}
+
+impl Quotas {
+    pub fn from_figment(
+        figment: &rocket::figment::Figment,
+    ) -> Result<Self, rocket::figment::Error> {
+        match figment.find_value("dem.quota") {
+            Ok(_) => figment.extract_inner("dem.quota"),
+            Err(_) => Ok(Quotas::default()),
+        }
+    }
+
+    /// What the guild and `user_id` use out of `images`, with the limits applying to them
+    pub fn usage<'a>(
+        &self,
+        images: impl IntoIterator<Item = &'a crate::image::ImageData>,
+        user_id: u64,
+    ) -> (QuotaUsage, QuotaUsage) {
+        let mut guild = QuotaUsage {
+            max_items: self.guild_items,
+            max_bytes: self.guild_bytes,
+            ..Default::default()
+        };
+        let mut user = QuotaUsage {
+            max_items: self.user_items,
+            max_bytes: self.user_bytes,
+            ..Default::default()
+        };
+        for image in images {
+            guild.items += 1;
+            guild.bytes += image.stored_size();
+            if image.uploader_id() == Some(user_id) {
+                user.items += 1;
+                user.bytes += image.stored_size();
+            }
+        }
+        (guild, user)
+    }
+
+    /// Why an upload of `size` bytes doesn't fit, if it doesn't
+    pub fn check(guild: &QuotaUsage, user: &QuotaUsage, size: u64) -> Result<(), String> {
+        let exceeds = |usage: &QuotaUsage| {
+            usage.max_items.map_or(false, |max| usage.items + 1 > max)
+                || usage
+                    .max_bytes
+                    .map_or(false, |max| usage.bytes + size > max)
+        };
+        if exceeds(guild) {
+            Err("The guild's store is full".to_string())
+        } else if exceeds(user) {
+            Err("You have used all of your storage in this guild".to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
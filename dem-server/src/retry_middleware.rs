@@ -1,11 +1,55 @@
-use anyhow::anyhow;
-use reqwest::{Request, Response};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use reqwest::{Request, Response, StatusCode};
 use reqwest_middleware::*;
 use task_local_extensions::Extensions;
 
 static MAXIMUM_NUMBER_OF_RETRIES: u32 = 10;
+/// Buckets past their reset are dropped once there are more than this
+const MAX_BUCKETS: usize = 1024;
+
+/// Returned (inside `Error::Middleware`) when a request is still rate limited
+/// after `MAXIMUM_NUMBER_OF_RETRIES` attempts
+#[derive(Debug)]
+pub struct RateLimitExhausted {
+    pub route: String,
+    pub retries: u32,
+}
+
+impl std::fmt::Display for RateLimitExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Still rate limited on {} after {} retries",
+            self.route, self.retries
+        )
+    }
+}
+
+impl std::error::Error for RateLimitExhausted {}
+
+#[derive(Default)]
+struct Bucket {
+    remaining: Option<u64>,
+    reset_at: Option<Instant>,
+}
 
-pub struct DiscordRateLimitMiddleware;
+/// Tracks Discord rate limit buckets and holds requests back before they are sent.
+///
+/// Buckets are keyed by the bucket hash Discord returns for a route (or the route itself
+/// until we know it), the major parameter and the token of the request.
+/// Requests on the same bucket wait in line on the bucket lock.
+/// Only requests to the REST API (`/api/...`) are tracked, CDN downloads go straight through.
+#[derive(Default)]
+pub struct DiscordRateLimitMiddleware {
+    routes: std::sync::Mutex<HashMap<String, String>>,
+    buckets: dashmap::DashMap<String, Arc<tokio::sync::Mutex<Bucket>>>,
+    global_until: std::sync::Mutex<Option<Instant>>,
+}
 
 #[async_trait]
 impl Middleware for DiscordRateLimitMiddleware {
@@ -23,17 +67,33 @@ impl Middleware for DiscordRateLimitMiddleware {
     }
 }
 
-
 impl DiscordRateLimitMiddleware {
-    /// This function will try to execute the request, if it fails
-    /// with an error classified as transient it will call itself
-    /// to retry the request.
+    /// Wait for the bucket of the request, send it and retry it
+    /// as long as Discord answers with a 429.
     async fn execute_with_retry<'a>(
         &'a self,
         req: Request,
         next: Next<'a>,
         ext: &'a mut Extensions,
     ) -> Result<Response> {
+        let (route, major) = match Self::route(&req) {
+            Some(r) => r,
+            None => return next.run(req, ext).await,
+        };
+
+        // Multipart bodies are streamed and can't be replayed, send them once
+        if req.try_clone().is_none() {
+            self.acquire(&route, &major).await;
+            let res = next.run(req, ext).await?;
+            return match self.update(&route, &major, &res).await {
+                None => Ok(res),
+                Some(_) => Err(Error::Middleware(anyhow::Error::new(RateLimitExhausted {
+                    route,
+                    retries: 0,
+                }))),
+            };
+        }
+
         let mut n_past_retries = 0;
         loop {
             // Cloning the request object before-the-fact is not ideal..
@@ -41,57 +101,349 @@ impl DiscordRateLimitMiddleware {
             // the Clone operation should be of constant complexity and not O(N)
             // since the byte abstraction is a shared pointer over a buffer.
             let duplicate_request = req.try_clone().ok_or_else(|| {
-                Error::Middleware(anyhow!(
-                    "Request object is not clonable. Are you passing a streaming body?".to_string()
+                Error::Middleware(anyhow::anyhow!(
+                    "Request object is not clonable. Are you passing a streaming body?"
                 ))
             })?;
 
-            let result = next.clone().run(duplicate_request, ext).await;
-
-            // We classify the response which will return None if not
-            // errors were returned.
-            break {
-                match result {
-                    Ok(r) if n_past_retries < MAXIMUM_NUMBER_OF_RETRIES => {
-                        // If the response failed and the error type was transient
-                        // we can safely try to retry the request.
-                        let retry_decicion = Self::should_retry(&r);
-                        if let Retry::After(dur) = retry_decicion {
-                            // Sleep the requested amount before we try again.
-                            tokio::time::sleep(dur).await;
-                            n_past_retries += 1;
-                            continue;
-                        } else {
-                            Ok(r)
-                        }
-                    }
-                    v => v,
+            self.acquire(&route, &major).await;
+            let res = next.clone().run(duplicate_request, ext).await?;
+            match self.update(&route, &major, &res).await {
+                None => break Ok(res),
+                Some(_) if n_past_retries >= MAXIMUM_NUMBER_OF_RETRIES => {
+                    break Err(Error::Middleware(anyhow::Error::new(RateLimitExhausted {
+                        route,
+                        retries: n_past_retries,
+                    })))
                 }
-            };
+                Some(wait) => {
+                    warn!("Rate limited on {route}, retrying in {wait:?}");
+                    tokio::time::sleep(wait).await;
+                    n_past_retries += 1;
+                }
+            }
         }
     }
 
-    fn should_retry(res: &Response) -> Retry {
-        if res.status().is_success() {
-            return Retry::No;
+    /// The route used to find the bucket (ids replaced by `:id`), and the major parameter
+    /// with the token of the request. `None` outside of the REST API.
+    fn route(req: &Request) -> Option<(String, String)> {
+        let mut segments = req.url().path_segments()?.peekable();
+        if segments.peek() != Some(&"api") {
+            return None;
         }
-        if let Some(Ok(v)) = res
+        let mut route = req.method().to_string();
+        let mut major = String::new();
+        let mut previous = "";
+        for segment in segments {
+            route.push('/');
+            if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+                if major.is_empty() && matches!(previous, "channels" | "guilds" | "webhooks") {
+                    major = segment.to_string();
+                }
+                route.push_str(":id");
+            } else {
+                route.push_str(segment);
+            }
+            previous = segment;
+        }
+        // Keep a hash of the token, not the token itself
+        let token = req
             .headers()
-            .get("X-RateLimit-Reset-After")
-            .map(|v| v.to_str())
+            .get(reqwest::header::AUTHORIZATION)
+            .map(|v| fxhash::hash64(v.as_bytes()))
+            .unwrap_or_default();
+        Some((route, format!("{major}:{token:x}")))
+    }
+
+    fn bucket(&self, route: &str, major: &str) -> Arc<tokio::sync::Mutex<Bucket>> {
+        let hash = self
+            .routes
+            .lock()
+            .unwrap()
+            .get(route)
+            .cloned()
+            .unwrap_or_else(|| route.to_string());
+        self.buckets
+            .entry(format!("{hash}:{major}"))
+            .or_default()
+            .clone()
+    }
+
+    /// Wait until the global limit and the bucket of the route allow a new request
+    async fn acquire(&self, route: &str, major: &str) {
+        let global_until = *self.global_until.lock().unwrap();
+        if let Some(until) = global_until {
+            tokio::time::sleep_until(until.into()).await;
+        }
+
+        let bucket = self.bucket(route, major);
+        let mut guard = bucket.lock().await;
+        let bucket = &mut *guard;
+        match bucket.remaining {
+            Some(0) => {
+                if let Some(reset_at) = bucket.reset_at {
+                    debug!("Bucket of {route} is empty, waiting for its reset");
+                    tokio::time::sleep_until(reset_at.into()).await;
+                }
+                // We don't know how big the bucket is until the next response
+                bucket.remaining = None;
+            }
+            Some(ref mut remaining) => *remaining -= 1,
+            None => {}
+        }
+    }
+
+    /// Drop the buckets nobody is waiting on whose reset is past, they don't limit anything
+    fn evict(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            Arc::strong_count(bucket) > 1
+                || bucket
+                    .try_lock()
+                    .map_or(true, |b| b.reset_at.map_or(false, |r| r > now))
+        });
+    }
+
+    /// Record the rate limit headers of a response.
+    /// Returns how long to wait before retrying if the request was rate limited.
+    async fn update(&self, route: &str, major: &str, res: &Response) -> Option<Duration> {
+        let header = |name: &str| res.headers().get(name).and_then(|v| v.to_str().ok());
+        let seconds = |name: &str| {
+            header(name)
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|v| v.is_finite() && *v >= 0.0)
+                .map(Duration::from_secs_f64)
+        };
+
+        if let Some(hash) = header("X-RateLimit-Bucket") {
+            self.routes
+                .lock()
+                .unwrap()
+                .insert(route.to_string(), hash.to_string());
+        }
+        let reset_after = seconds("X-RateLimit-Reset-After");
         {
-            let dur_f32: f32 = v.parse().unwrap();
-            if dur_f32 <= 0.0 {
-                return Retry::No;
+            let bucket = self.bucket(route, major);
+            let mut guard = bucket.lock().await;
+            let bucket = &mut *guard;
+            if let Some(remaining) = header("X-RateLimit-Remaining").and_then(|v| v.parse().ok()) {
+                bucket.remaining = Some(remaining);
+            }
+            if let Some(reset_after) = reset_after {
+                bucket.reset_at = Some(Instant::now() + reset_after);
             }
-            return Retry::After(std::time::Duration::from_secs_f32(dur_f32));
-        } else {
-            Retry::No
         }
+        if self.buckets.len() > MAX_BUCKETS {
+            self.evict();
+        }
+
+        if res.status() != StatusCode::TOO_MANY_REQUESTS {
+            return None;
+        }
+        let retry_after = seconds("Retry-After")
+            .or(reset_after)
+            .unwrap_or(Duration::from_secs(1));
+        if header("X-RateLimit-Global").is_some() || header("X-RateLimit-Scope") == Some("global") {
+            warn!("Hit the global rate limit, pausing every request for {retry_after:?}");
+            *self.global_until.lock().unwrap() = Some(Instant::now() + retry_after);
+        }
+        Some(retry_after)
     }
 }
 
-enum Retry {
-    After(std::time::Duration),
-    No,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Read a request up to the end of its body, and return its lowercased head
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> String {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            data.extend_from_slice(&buf[..n]);
+            let end = match data.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(end) => end,
+                None if n == 0 => return String::new(),
+                None => continue,
+            };
+            let head = String::from_utf8_lossy(&data[..end]).to_lowercase();
+            let body = &data[end + 4..];
+            let length = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length:"))
+                .and_then(|l| l.trim().parse::<usize>().ok());
+            let complete = match length {
+                Some(length) => body.len() >= length,
+                None if head.contains("transfer-encoding: chunked") => body.ends_with(b"0\r\n\r\n"),
+                None => true,
+            };
+            if complete || n == 0 {
+                return head;
+            }
+        }
+    }
+
+    /// A server answering every request with the status line and headers `respond`
+    /// returns for the path of the request and the number of requests seen before
+    async fn fake_server(
+        respond: impl Fn(&str, usize) -> String + Send + Sync + 'static,
+    ) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let respond = Arc::new(respond);
+        tokio::spawn({
+            let count = count.clone();
+            async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let count = count.clone();
+                    let respond = respond.clone();
+                    tokio::spawn(async move {
+                        let head = read_request(&mut stream).await;
+                        let path = head.split_whitespace().nth(1).unwrap_or_default();
+                        let head = respond(path, count.fetch_add(1, Ordering::SeqCst));
+                        let response = format!(
+                            "HTTP/1.1 {head}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                        );
+                        let _ = stream.write_all(response.as_bytes()).await;
+                    });
+                }
+            }
+        });
+        (url, count)
+    }
+
+    fn client() -> reqwest_middleware::ClientWithMiddleware {
+        reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
+            .with(DiscordRateLimitMiddleware::default())
+            .build()
+    }
+
+    fn is_exhausted(e: &Error) -> bool {
+        matches!(e, Error::Middleware(e) if e.downcast_ref::<RateLimitExhausted>().is_some())
+    }
+
+    #[tokio::test]
+    async fn empty_bucket_waits_for_reset() {
+        let (url, count) = fake_server(|_, _| {
+            "200 OK\r\nx-ratelimit-bucket: emojis\r\nx-ratelimit-remaining: 0\r\n\
+             x-ratelimit-reset-after: 0.5"
+                .to_string()
+        })
+        .await;
+        let client = client();
+        let url = format!("{url}/api/v10/guilds/1/emojis");
+        client.get(&url).send().await.unwrap();
+        let start = Instant::now();
+        client.get(&url).send().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn buckets_are_per_major_parameter_and_token() {
+        let (url, _) = fake_server(|_, _| {
+            "200 OK\r\nx-ratelimit-remaining: 0\r\nx-ratelimit-reset-after: 2".to_string()
+        })
+        .await;
+        let client = client();
+        client
+            .get(format!("{url}/api/v10/guilds/1/emojis"))
+            .bearer_auth("bot")
+            .send()
+            .await
+            .unwrap();
+        let start = Instant::now();
+        client
+            .get(format!("{url}/api/v10/guilds/2/emojis"))
+            .bearer_auth("bot")
+            .send()
+            .await
+            .unwrap();
+        client
+            .get(format!("{url}/api/v10/guilds/1/emojis"))
+            .bearer_auth("user")
+            .send()
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn global_limit_holds_every_route() {
+        let (url, count) = fake_server(|path, n| match (path, n) {
+            ("/api/v10/users/@me", 0) => {
+                "429 Too Many Requests\r\nx-ratelimit-global: true\r\nretry-after: 0.5".to_string()
+            }
+            _ => "200 OK".to_string(),
+        })
+        .await;
+        let client = client();
+        let start = Instant::now();
+        let limited = tokio::spawn({
+            let client = client.clone();
+            let url = format!("{url}/api/v10/users/@me");
+            async move { client.get(url).send().await.unwrap().status() }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let other = client
+            .get(format!("{url}/api/v10/guilds/1"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(other.status(), StatusCode::OK);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(limited.await.unwrap(), StatusCode::OK);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn repeated_429_is_exhausted() {
+        let (url, count) =
+            fake_server(|_, _| "429 Too Many Requests\r\nretry-after: 0.01".to_string()).await;
+        let err = client()
+            .get(format!("{url}/api/v10/guilds/1/emojis"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(is_exhausted(&err));
+        assert_eq!(
+            count.load(Ordering::SeqCst),
+            MAXIMUM_NUMBER_OF_RETRIES as usize + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn multipart_429_is_exhausted() {
+        let (url, count) =
+            fake_server(|_, _| "429 Too Many Requests\r\nretry-after: 0.01".to_string()).await;
+        let form = reqwest::multipart::Form::new()
+            .part("file", reqwest::multipart::Part::bytes(vec![0u8; 64]));
+        let err = client()
+            .post(format!("{url}/api/v10/guilds/1/stickers"))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap_err();
+        assert!(is_exhausted(&err));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn cdn_requests_are_not_tracked() {
+        let (url, count) =
+            fake_server(|_, _| "429 Too Many Requests\r\nretry-after: 5".to_string()).await;
+        let res = client()
+            .get(format!("{url}/emojis/1.png"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
 }
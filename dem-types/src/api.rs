@@ -8,4 +8,268 @@ pub struct UserLogin {
     pub discriminator: String,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct EmojiPatch {
+    pub name: Option<String>,
+    pub roles: Option<Vec<u64>>,
+}
+
+/// Changes to an image of the store, `description` and `tags` are for stickers only
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct StoredImagePatch {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub tags: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct PublishedItem {
+    pub emoji: Option<crate::discord::EmojiItem>,
+    pub sticker: Option<crate::discord::StickerItem>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, JsonSchema)]
+pub enum ConflictStrategy {
+    #[default]
+    Skip,
+    Rename,
+    Replace,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct CopyRequest {
+    pub target: u64,
+    #[serde(default)]
+    pub emojis: Vec<u64>,
+    #[serde(default)]
+    pub stickers: Vec<u64>,
+    #[serde(default)]
+    pub on_conflict: ConflictStrategy,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum CopyStatus {
+    Copied,
+    Renamed,
+    Replaced,
+    Skipped,
+    Failed,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct CopyItemResult {
+    pub source_id: u64,
+    pub name: String,
+    pub status: CopyStatus,
+    pub new_id: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl CopyItemResult {
+    pub fn failed(source_id: u64, name: String, error: String) -> Self {
+        CopyItemResult {
+            source_id,
+            name,
+            status: CopyStatus::Failed,
+            new_id: None,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct CopyResult {
+    pub emojis: Vec<CopyItemResult>,
+    pub stickers: Vec<CopyItemResult>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum ContentSource {
+    /// An image of the guild's store, `id` is its uuid
+    Store,
+    /// An emoji live in the guild, `id` is the emoji id
+    Emoji,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct ContentRef {
+    pub source: ContentSource,
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct Duplicate {
+    pub item: ContentRef,
+    /// From `0.0` to `1.0`, `1.0` being visually identical
+    pub similarity: f32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct UploadResult {
+    pub uuid: String,
+    /// Existing content looking like the upload
+    pub duplicates: Vec<Duplicate>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct DuplicatePair {
+    pub first: ContentRef,
+    pub second: ContentRef,
+    pub similarity: f32,
+}
 
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, JsonSchema)]
+pub struct QuotaUsage {
+    pub items: u64,
+    /// Bytes of the served images
+    pub bytes: u64,
+    /// `None` when there is no limit
+    pub max_items: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, JsonSchema)]
+pub struct StoreUsage {
+    pub guild: QuotaUsage,
+    /// What the current user uploaded in the guild
+    pub user: QuotaUsage,
+    /// Uploads of the current user in the guild during the current window
+    pub uploads: u64,
+    pub max_uploads: Option<u64>,
+    pub window_secs: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, JsonSchema)]
+pub struct SlotUsage {
+    pub used: u64,
+    pub available: u64,
+    pub total: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, JsonSchema)]
+pub struct GuildCapacity {
+    pub premium_tier: u8,
+    pub static_emojis: SlotUsage,
+    pub animated_emojis: SlotUsage,
+    pub stickers: SlotUsage,
+}
+
+/// What an API token is allowed to do, `Admin` allows everything
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum ApiScope {
+    /// Every `GET` route
+    Read,
+    /// Upload to the store and edit or delete the uploads
+    Upload,
+    /// Publish stored images and copy emojis between guilds
+    Publish,
+    Admin,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct ApiTokenCreate {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Only allow the token to act in this guild
+    #[serde(default)]
+    pub guild: Option<u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub guild: Option<u64>,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct ApiTokenCreated {
+    /// The secret to send as `Authorization: Bearer <token>`, it is only shown once
+    pub token: String,
+    pub info: ApiTokenInfo,
+}
+
+/// An emoji of a manifest
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct ManifestEmoji {
+    pub name: String,
+    /// Pin the entry to an existing emoji, to rename it for instance
+    #[serde(default)]
+    pub id: Option<u64>,
+    /// Roles allowed to use the emoji, everyone when empty
+    #[serde(default)]
+    pub roles: Vec<u64>,
+    /// Base64 encoded PNG, GIF, JPEG or WebP
+    pub image: String,
+}
+
+/// A sticker of a manifest
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct ManifestSticker {
+    pub name: String,
+    /// Pin the entry to an existing sticker, to rename it for instance
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub tags: String,
+    /// Base64 encoded 320x320 PNG, APNG or Lottie
+    pub image: String,
+}
+
+/// The emojis and stickers a guild should have
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct SyncRequest {
+    #[serde(default)]
+    pub emojis: Vec<ManifestEmoji>,
+    #[serde(default)]
+    pub stickers: Vec<ManifestSticker>,
+    /// Delete the emojis and stickers missing from the manifest
+    #[serde(default)]
+    pub prune: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum SyncKind {
+    Emoji,
+    Sticker,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+pub enum SyncAction {
+    /// Already up to date
+    Keep,
+    Create,
+    /// Same image under another name, the roles or metadata may change too
+    Rename,
+    /// Only the roles, or the description and tags of a sticker, change
+    Update,
+    /// The image changed, Discord can't edit it so it is deleted and created again
+    Replace,
+    Delete,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct SyncItem {
+    pub kind: SyncKind,
+    pub action: SyncAction,
+    /// Name in the manifest, or of the deleted item
+    pub name: String,
+    /// Current name of a renamed item
+    pub previous_name: Option<String>,
+    /// Id of the existing item, or of the created one once applied
+    pub id: Option<u64>,
+    /// Why applying the item failed
+    pub error: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, JsonSchema)]
+pub struct SyncResult {
+    /// `false` for a dry-run
+    pub applied: bool,
+    pub items: Vec<SyncItem>,
+}
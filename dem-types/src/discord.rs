@@ -1,5 +1,14 @@
 use rocket_okapi::JsonSchema;
 
+pub const ADMINISTRATOR: u64 = 1 << 3;
+pub const MANAGE_EMOJIS_AND_STICKERS: u64 = 1 << 30;
+pub const ALL_PERMISSIONS: u64 = u64::MAX;
+
+/// Check a set of permission bits, administrators are allowed everything
+pub fn has_permission(permissions: u64, permission: u64) -> bool {
+    permissions & ADMINISTRATOR != 0 || permissions & permission == permission
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, JsonSchema)]
 pub struct EmojiItem {
     #[serde(deserialize_with = "deserialize_str")]
@@ -8,6 +17,9 @@ pub struct EmojiItem {
     pub available: bool,
     pub managed: bool,
     pub name: String,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_str_vec")]
+    pub roles: Vec<u64>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, JsonSchema)]
@@ -16,6 +28,43 @@ pub struct StickerItem {
     pub id: u64,
     pub name: String,
     pub description: Option<String>,
+    #[serde(default)]
+    pub tags: Option<String>,
+    #[serde(default)]
+    pub format_type: Option<u8>,
+}
+
+impl EmojiItem {
+    pub fn cdn_url(&self) -> String {
+        format!(
+            "https://cdn.discordapp.com/emojis/{}.{}",
+            self.id,
+            if self.animated { "gif" } else { "png" }
+        )
+    }
+}
+
+impl StickerItem {
+    /// File extension matching the sticker's `format_type`
+    pub fn extension(&self) -> &'static str {
+        match self.format_type {
+            Some(3) => "json",
+            Some(4) => "gif",
+            _ => "png",
+        }
+    }
+
+    pub fn cdn_url(&self) -> String {
+        match self.format_type {
+            // Lottie stickers aren't served by the media proxy
+            Some(3) => format!("https://discord.com/stickers/{}.json", self.id),
+            _ => format!(
+                "https://media.discordapp.net/stickers/{}.{}",
+                self.id,
+                self.extension()
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, JsonSchema)]
@@ -23,19 +72,119 @@ pub struct PartialGuild {
     #[serde(deserialize_with = "deserialize_str")]
     pub id: u64,
     pub name: String,
-    pub icon: String,
+    pub icon: Option<String>,
     pub emojis: Vec<EmojiItem>,
     pub stickers: Vec<StickerItem>,
     pub description: Option<String>,
+    #[serde(deserialize_with = "deserialize_str")]
+    pub owner_id: u64,
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    #[serde(default)]
     pub members: Vec<GuildMember>,
+    /// Boost level, from `0` to `3`
+    #[serde(default)]
+    pub premium_tier: u8,
+}
+
+impl PartialGuild {
+    /// Static emojis, and animated emojis, the guild can have at its boost level
+    pub fn emoji_slots(&self) -> u64 {
+        match self.premium_tier {
+            0 => 50,
+            1 => 100,
+            2 => 150,
+            _ => 250,
+        }
+    }
+
+    pub fn sticker_slots(&self) -> u64 {
+        match self.premium_tier {
+            0 => 5,
+            1 => 15,
+            2 => 30,
+            _ => 60,
+        }
+    }
+
+    /// Used and available slots, emojis managed by an integration don't take any
+    pub fn capacity(&self) -> crate::api::GuildCapacity {
+        let slots = |used: usize, total: u64| crate::api::SlotUsage {
+            used: used as u64,
+            available: total.saturating_sub(used as u64),
+            total,
+        };
+        let emojis = self.emojis.iter().filter(|e| !e.managed);
+        crate::api::GuildCapacity {
+            premium_tier: self.premium_tier,
+            static_emojis: slots(
+                emojis.clone().filter(|e| !e.animated).count(),
+                self.emoji_slots(),
+            ),
+            animated_emojis: slots(emojis.filter(|e| e.animated).count(), self.emoji_slots()),
+            stickers: slots(self.stickers.len(), self.sticker_slots()),
+        }
+    }
+
+    /// Compute the guild-level permissions of a member, like Discord does:
+    /// the owner and administrators get everything, everyone else gets the union
+    /// of `@everyone` and their roles.
+    /// Returns `None` when the member isn't in the cache.
+    pub fn permissions_of(&self, user_id: u64) -> Option<u64> {
+        if user_id == self.owner_id {
+            return Some(ALL_PERMISSIONS);
+        }
+        let member = self.members.iter().find(|m| m.user.id == user_id)?;
+        // The @everyone role shares its id with the guild
+        let permissions = self
+            .roles
+            .iter()
+            .filter(|r| r.id == self.id || member.roles.contains(&r.id))
+            .fold(0, |acc, r| acc | r.permissions);
+        if permissions & ADMINISTRATOR != 0 {
+            Some(ALL_PERMISSIONS)
+        } else {
+            Some(permissions)
+        }
+    }
+
+    pub fn upsert_member(&mut self, member: GuildMember) {
+        match self.members.iter_mut().find(|m| m.user.id == member.user.id) {
+            Some(m) => *m = member,
+            None => self.members.push(member),
+        }
+    }
+
+    pub fn upsert_role(&mut self, role: Role) {
+        match self.roles.iter_mut().find(|r| r.id == role.id) {
+            Some(r) => *r = role,
+            None => self.roles.push(role),
+        }
+    }
+
+    pub fn remove_role(&mut self, role_id: u64) {
+        self.roles.retain(|r| r.id != role_id);
+        for member in self.members.iter_mut() {
+            member.roles.retain(|&r| r != role_id);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, JsonSchema)]
+pub struct Role {
+    #[serde(deserialize_with = "deserialize_str")]
+    pub id: u64,
+    pub name: String,
+    #[serde(deserialize_with = "deserialize_str")]
+    pub permissions: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, JsonSchema)]
 pub struct GuildMember {
     pub user: User,
     #[serde(default)]
-    #[serde(deserialize_with = "deserialize_str")]
-    pub permissions: u64,
+    #[serde(deserialize_with = "deserialize_str_vec")]
+    pub roles: Vec<u64>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize, JsonSchema)]
@@ -50,7 +199,7 @@ pub struct PartialGuildWithPermission {
     #[serde(deserialize_with = "deserialize_str")]
     pub id: u64,
     pub name: String,
-    pub icon: String,
+    pub icon: Option<String>,
     pub emojis: Vec<EmojiItem>,
     pub stickers: Vec<StickerItem>,
     pub description: Option<String>,
@@ -79,3 +228,14 @@ where
     let s = <String>::deserialize(deserializer)?;
     s.parse().map_err(serde::de::Error::custom)
 }
+
+fn deserialize_str_vec<'de, D>(deserializer: D) -> Result<Vec<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    let v = <Vec<String>>::deserialize(deserializer)?;
+    v.into_iter()
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
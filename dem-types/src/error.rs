@@ -102,6 +102,16 @@ pub struct ErrResponse {
     err: UserError,
 }
 
+impl ErrResponse {
+    pub fn code(&self) -> Error {
+        self.err.code
+    }
+
+    pub fn description(&self) -> &str {
+        &self.err.description
+    }
+}
+
 define_error! {
     pub enum Error {
         Internal = {
@@ -119,6 +129,10 @@ define_error! {
         InvalidRequest = {
             description: "invalid request",
             code: 3,
+        },
+        QuotaExceeded = {
+            description: "storage quota exceeded",
+            code: 4,
         }
     }
 }